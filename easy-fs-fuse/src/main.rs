@@ -0,0 +1,425 @@
+use std::fs::{read_dir, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{App, Arg, ArgMatches};
+use easy_fs::{
+    fsck, set_clock, BlockDevice, EasyFileSystem, EfsError, FsckReport, Inode, BLOCK_SZ,
+    FEATURE_CHECKSUMS, FEATURE_COMPRESSION, FEATURE_DIR_INDEX, FEATURE_EXTENTS,
+    FEATURE_INLINE_DATA,
+};
+
+/// 镜像文件的总块数, 16MiB
+const IMG_BLOCKS: u32 = 16 * 2048;
+/// inode位图的块数, 可以容纳4096个inode
+const INODE_BITMAP_BLOCKS: u32 = 1;
+
+/// 以宿主机上的文件作为块设备
+struct BlockFile(Mutex<File>);
+
+/// 读写镜像文件失败或者没有读写完整的块时都作为块设备读写失败
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| EfsError::IoError)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> easy_fs::Result<()> {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|_| EfsError::IoError)
+    }
+
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) -> easy_fs::Result<()> {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((start_block_id * BLOCK_SZ) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| EfsError::IoError)
+    }
+
+    fn flush(&self) -> easy_fs::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .sync_data()
+            .map_err(|_| EfsError::IoError)
+    }
+}
+
+/// 将easy-fs的错误转换为宿主机的io错误
+fn efs_error(err: EfsError) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// 以宿主机的系统时间作为文件系统的时钟
+fn host_clock() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn main() {
+    set_clock(host_clock);
+    let matches = App::new("EasyFileSystem packer")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .takes_value(true)
+                .help("Executable source dir(with backslash)"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .short("t")
+                .long("target")
+                .takes_value(true)
+                .help("Executable target dir(with backslash)"),
+        )
+        .arg(
+            Arg::with_name("extents")
+                .short("e")
+                .long("extents")
+                .conflicts_with("check")
+                .help("Store file data as extents instead of block pointers"),
+        )
+        .arg(
+            Arg::with_name("checksums")
+                .short("k")
+                .long("checksums")
+                .conflicts_with("check")
+                .help("Protect blocks with CRC32 checksums"),
+        )
+        .arg(
+            Arg::with_name("inline")
+                .short("i")
+                .long("inline")
+                .conflicts_with("check")
+                .help("Store the contents of tiny files inside their inodes"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .short("z")
+                .long("compress")
+                .conflicts_with("check")
+                .help("Compress the contents of files in 4KiB chunks"),
+        )
+        .arg(
+            Arg::with_name("dir-index")
+                .short("x")
+                .long("dir-index")
+                .conflicts_with("check")
+                .help("Index large directories by hashing their entry names"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .short("c")
+                .long("check")
+                .takes_value(true)
+                .conflicts_with_all(&["source", "target"])
+                .help("Check an existing easy-fs image instead of packing"),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .short("r")
+                .long("repair")
+                .requires("check")
+                .help("Repair the problems found when checking"),
+        )
+        .arg(
+            Arg::with_name("accept-checksums")
+                .long("accept-checksums")
+                .requires("repair")
+                .help("Accept the current content of blocks that do not match their checksums"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .takes_value(true)
+                .conflicts_with_all(&["source", "target", "check"])
+                .help("Manage the snapshots or the size of an existing easy-fs image"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .takes_value(true)
+                .requires("image")
+                .help("Take a snapshot of the image with the given name"),
+        )
+        .arg(
+            Arg::with_name("rollback")
+                .long("rollback")
+                .takes_value(true)
+                .requires("image")
+                .conflicts_with("snapshot")
+                .help("Roll the image back to the named snapshot"),
+        )
+        .arg(
+            Arg::with_name("delete-snapshot")
+                .long("delete-snapshot")
+                .takes_value(true)
+                .requires("image")
+                .conflicts_with_all(&["snapshot", "rollback"])
+                .help("Delete the named snapshot"),
+        )
+        .arg(
+            Arg::with_name("resize")
+                .long("resize")
+                .takes_value(true)
+                .requires("image")
+                .conflicts_with_all(&["snapshot", "rollback", "delete-snapshot"])
+                .help("Grow or shrink the image to the given number of blocks"),
+        )
+        .get_matches();
+    if let (Some(image_path), Some(blocks)) =
+        (matches.value_of("image"), matches.value_of("resize"))
+    {
+        easy_fs_resize(image_path, blocks).expect("Error when resizing easy-fs!");
+    } else if let Some(image_path) = matches.value_of("image") {
+        easy_fs_snapshot(image_path, &matches).expect("Error when managing snapshots!");
+    } else if let Some(image_path) = matches.value_of("check") {
+        let clean = easy_fs_check(
+            image_path,
+            matches.is_present("repair"),
+            matches.is_present("accept-checksums"),
+        )
+        .expect("Error when checking easy-fs!");
+        if !clean {
+            std::process::exit(1);
+        }
+    } else {
+        easy_fs_pack(&matches).expect("Error when packing easy-fs!");
+    }
+}
+
+/// 创建fs.img并将用户程序的ELF文件打包到根目录下
+fn easy_fs_pack(matches: &ArgMatches) -> io::Result<()> {
+    let src_path = matches.value_of("source").unwrap();
+    let target_path = matches.value_of("target").unwrap();
+    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(format!("{}{}", target_path, "fs.img"))?;
+        f.set_len(IMG_BLOCKS as u64 * BLOCK_SZ as u64)?;
+        f
+    })));
+    let mut features = 0;
+    if matches.is_present("extents") {
+        features |= FEATURE_EXTENTS;
+    }
+    if matches.is_present("checksums") {
+        features |= FEATURE_CHECKSUMS;
+    }
+    if matches.is_present("inline") {
+        features |= FEATURE_INLINE_DATA;
+    }
+    if matches.is_present("compress") {
+        features |= FEATURE_COMPRESSION;
+    }
+    if matches.is_present("dir-index") {
+        features |= FEATURE_DIR_INDEX;
+    }
+    let efs = EasyFileSystem::create_with_features(
+        Arc::clone(&block_file),
+        IMG_BLOCKS,
+        INODE_BITMAP_BLOCKS,
+        features,
+    )
+    .map_err(efs_error)?;
+    let root_inode = Arc::new(Inode::root_inode(&efs));
+
+    // 和os/build.rs一样, 根据源码目录确定应用名称
+    let apps: Vec<_> = read_dir(src_path)?
+        .map(|dir_entry| {
+            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
+            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
+            name_with_ext
+        })
+        .collect();
+    for app in apps {
+        // 从宿主机读取ELF文件
+        let mut host_file = File::open(format!("{}{}", target_path, app))?;
+        let mut all_data: Vec<u8> = Vec::new();
+        host_file.read_to_end(&mut all_data)?;
+        // 在easy-fs中创建同名文件并写入
+        let inode = root_inode.create(app.as_str()).map_err(efs_error)?;
+        inode.write_at(0, all_data.as_slice()).map_err(efs_error)?;
+        // 用户程序需要可执行权限
+        inode.chmod(0o755).map_err(efs_error)?;
+    }
+    for app in root_inode.ls().map_err(efs_error)? {
+        println!("{}", app);
+    }
+    Ok(())
+}
+
+/// 创建、回滚或删除一个已经存在的easy-fs镜像的快照, 之后列出镜像中的所有快照
+fn easy_fs_snapshot(image_path: &str, matches: &ArgMatches) -> io::Result<()> {
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(image_path)?,
+    )));
+    let efs = EasyFileSystem::open(Arc::clone(&block_file)).map_err(efs_error)?;
+    let mut fs = efs.lock();
+    if let Some(name) = matches.value_of("snapshot") {
+        fs.create_snapshot(name).map_err(efs_error)?;
+    } else if let Some(name) = matches.value_of("rollback") {
+        fs.rollback_snapshot(name).map_err(efs_error)?;
+    } else if let Some(name) = matches.value_of("delete-snapshot") {
+        fs.delete_snapshot(name).map_err(efs_error)?;
+    }
+    for snapshot in fs.list_snapshots().map_err(efs_error)? {
+        println!("{}\t{}", snapshot.name, snapshot.created);
+    }
+    Ok(())
+}
+
+/// 将一个已经存在的easy-fs镜像扩大或者缩小到blocks个块, 镜像文件的大小随之改变
+fn easy_fs_resize(image_path: &str, blocks: &str) -> io::Result<()> {
+    let new_total_blocks: u32 = blocks
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid number of blocks"))?;
+    let image = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let new_len = new_total_blocks as u64 * BLOCK_SZ as u64;
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(image.try_clone()?)));
+    let efs = EasyFileSystem::open(Arc::clone(&block_file)).map_err(efs_error)?;
+    let mut fs = efs.lock();
+    let total_blocks = fs.total_blocks().map_err(efs_error)? as u32;
+    if new_total_blocks >= total_blocks {
+        // 新增的块需要先出现在镜像文件中才能被写入
+        if image.metadata()?.len() < new_len {
+            image.set_len(new_len)?;
+        }
+        fs.grow(new_total_blocks).map_err(efs_error)?;
+    } else {
+        fs.shrink(new_total_blocks).map_err(efs_error)?;
+        image.set_len(new_len)?;
+    }
+    println!("{}: {} blocks", image_path, new_total_blocks);
+    Ok(())
+}
+
+/// 检查一个已经存在的easy-fs镜像, 返回镜像是否没有问题
+///
+/// 与校验和不一致的块不会被自动修复, 只有accept为true时才接受它们的当前内容并继续修复
+fn easy_fs_check(image_path: &str, repair: bool, accept: bool) -> io::Result<bool> {
+    let block_file: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(repair)
+            .open(image_path)?,
+    )));
+    // 只检查时以只读方式打开, 日志只重放到块缓存中, 镜像不会被修改
+    let opened = if repair {
+        EasyFileSystem::open(Arc::clone(&block_file))
+    } else {
+        EasyFileSystem::open_read_only(Arc::clone(&block_file))
+    };
+    let efs = match opened {
+        Ok(efs) => efs,
+        Err(err) => {
+            println!("{}: {}", image_path, err);
+            return Ok(false);
+        }
+    };
+    let report = fsck(&efs, repair).map_err(efs_error)?;
+    print_report(&report);
+    let mut remaining = report.bad_checksums.len();
+    if accept && remaining > 0 {
+        efs.lock()
+            .accept_checksums(&report.bad_checksums)
+            .map_err(efs_error)?;
+        println!(
+            "{}: accepted the current content of {} blocks",
+            image_path, remaining
+        );
+        // 接受之后才能检查和修复其余的问题
+        let rest = fsck(&efs, repair).map_err(efs_error)?;
+        print_report(&rest);
+        remaining = rest.bad_checksums.len();
+    }
+    if report.is_clean() {
+        println!("{}: clean", image_path);
+    } else if remaining > 0 {
+        println!(
+            "{}: blocks that do not match their checksums are left untouched, \
+             inspect them and rerun with --repair --accept-checksums",
+            image_path
+        );
+    } else if repair {
+        println!("{}: repaired", image_path);
+    }
+    Ok(report.is_clean())
+}
+
+/// 打印文件系统检查的结果
+fn print_report(report: &FsckReport) {
+    for inode_id in report.leaked_inodes.iter() {
+        println!("inode {} is allocated but unreachable", inode_id);
+    }
+    for inode_id in report.unallocated_inodes.iter() {
+        println!("inode {} is reachable but not allocated", inode_id);
+    }
+    for block_id in report.leaked_blocks.iter() {
+        println!("block {} is allocated but unused", block_id);
+    }
+    for block_id in report.unallocated_blocks.iter() {
+        println!("block {} is used but not allocated", block_id);
+    }
+    for block_id in report.doubly_owned_blocks.iter() {
+        println!("block {} is used by more than one inode", block_id);
+    }
+    for (inode_id, block_id) in report.bad_blocks.iter() {
+        println!(
+            "inode {} points to block {} outside the data area",
+            inode_id, block_id
+        );
+    }
+    for (dir_inode_id, name) in report.dangling_entries.iter() {
+        println!(
+            "entry {} in directory {} points to a free inode",
+            name, dir_inode_id
+        );
+    }
+    for (dir_inode_id, name) in report.misplaced_entries.iter() {
+        println!(
+            "entry {} in directory {} is outside its hash probe range",
+            name, dir_inode_id
+        );
+    }
+    for (inode_id, nlink, actual) in report.bad_link_counts.iter() {
+        println!(
+            "inode {} has link count {} but {} entries refer to it",
+            inode_id, nlink, actual
+        );
+    }
+    for block_id in report.bad_checksums.iter() {
+        println!("block {} does not match its checksum", block_id);
+    }
+    for (block_id, recorded, actual) in report.bad_refcounts.iter() {
+        println!(
+            "block {} has reference count {} but {} extra owners",
+            block_id, recorded, actual
+        );
+    }
+    if let Some((recorded, actual)) = report.bad_free_inodes {
+        println!(
+            "superblock records {} free inodes but the bitmap has {}",
+            recorded, actual
+        );
+    }
+    if let Some((recorded, actual)) = report.bad_free_data_blocks {
+        println!(
+            "superblock records {} free data blocks but the bitmap has {}",
+            recorded, actual
+        );
+    }
+}
@@ -1,9 +1,9 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
-use crate::{block_dev::BlockDevice, get_block_cache, BLOCK_BITS};
+use crate::{block_dev::BlockDevice, get_block_cache, EfsError, Result, BLOCK_BITS};
 
 /// 位图
-///    
+///
 /// 用于检查后续的块是否已被使用
 /// 每个bit表示一个块,0表示未使用、1表示已使用
 pub struct Bitmap {
@@ -11,6 +11,10 @@ pub struct Bitmap {
     start_block_id: usize,
     // 位图连续块数量
     blocks: usize,
+    // 有效的位数, 位图最后一个块中超出这个数量的位不会被分配
+    size: usize,
+    // 下一次分配开始查找的位置
+    hint: usize,
 }
 
 /// 表示位图区域的磁盘数据结构
@@ -20,66 +24,313 @@ pub struct Bitmap {
 type BitmapBlock = [u64; 64];
 
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
-        Self {
+    /// 位数超出blocks个位图块的容量时返回EfsError::InvalidArgument
+    pub fn new(start_block_id: usize, blocks: usize, size: usize) -> Result<Self> {
+        if size > blocks * BLOCK_BITS {
+            return Err(EfsError::InvalidArgument);
+        }
+        Ok(Self {
             start_block_id,
             blocks,
-        }
+            size,
+            hint: 0,
+        })
+    }
+
+    /// 位图可以表示的最大位数
+    pub fn maximum(&self) -> usize {
+        self.blocks * BLOCK_BITS
+    }
+
+    /// 位图占用的所有块编号
+    pub fn block_range(&self) -> core::ops::Range<usize> {
+        self.start_block_id..self.start_block_id + self.blocks
+    }
+
+    /// 位图中有效的位数
+    pub fn size(&self) -> usize {
+        self.size
     }
 
     /// 从位图中分配一个位
     ///
-    /// 返回的是bit所在的位置,等同于索引节点的数据块编号
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_offset in 0..self.blocks {
-            let block_id = block_offset + self.start_block_id;
-            return get_block_cache(block_id, Arc::clone(block_device))
-                .lock()
-                .modify(0, |bitmap_block: &mut BitmapBlock| {
-                    if let Some((idx, bitmap)) = bitmap_block
-                        .iter()
-                        .enumerate()
-                        .find(|(_, bits64)| **bits64 != u64::MAX)
-                    {
-                        // 获取bitmap的二进制表示中1的个数
-                        // 即已经分配了多少块
-                        let alloc_size = bitmap.trailing_ones() as usize;
-                        bitmap_block[idx] |= 1u64 << alloc_size;
-
-                        Some(block_offset * BLOCK_BITS + idx * 64 + alloc_size as usize)
-                    } else {
-                        None
-                    }
-                });
+    /// 返回的是bit所在的位置,等同于索引节点的数据块编号, 没有空闲的位时返回None
+    pub fn alloc(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<Option<usize>> {
+        self.alloc_contiguous(block_device, 1)
+    }
+
+    /// 从位图中分配count个连续的位, 返回第一个位的位置
+    ///
+    /// 采用循环首次适应: 从上一次分配结束的位置开始查找, 找不到时再从头查找
+    pub fn alloc_contiguous(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+    ) -> Result<Option<usize>> {
+        if count == 0 || count > self.size {
+            return Ok(None);
+        }
+        let start = match self.find_free_run(block_device, self.hint, self.size, count)? {
+            Some(start) => start,
+            None => match self.find_free_run(
+                block_device,
+                0,
+                (self.hint + count).min(self.size),
+                count,
+            )? {
+                Some(start) => start,
+                None => return Ok(None),
+            },
+        };
+        for bit in start..start + count {
+            self.set_allocated(block_device, bit, true)?;
+        }
+        self.hint = (start + count) % self.size;
+        Ok(Some(start))
+    }
+
+    /// 在[from, to)范围内查找count个连续的空闲位
+    fn find_free_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        to: usize,
+        count: usize,
+    ) -> Result<Option<usize>> {
+        let mut run_start = from;
+        let mut bit = from;
+        while bit < to {
+            let (block_offset, bitmap_idx, _) = decomposition(bit);
+            let bits64 =
+                get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))?
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| bitmap_block[bitmap_idx])?;
+            let word_end = ((bit / 64 + 1) * 64).min(to);
+            if bits64 == u64::MAX {
+                // 整个u64都已经被分配
+                bit = word_end;
+                run_start = bit;
+                continue;
+            }
+            while bit < word_end {
+                if bits64 & (1u64 << (bit % 64)) != 0 {
+                    run_start = bit + 1;
+                } else if bit + 1 - run_start == count {
+                    return Ok(Some(run_start));
+                }
+                bit += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// 从上一次分配结束的位置开始查找count个空闲的位, 不修改位图
+    ///
+    /// 用于先写入内容、再标记为已分配的场景, 空闲的位不足时返回None
+    pub fn find_free(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+    ) -> Result<Option<Vec<usize>>> {
+        let mut found = Vec::with_capacity(count);
+        let mut word: Option<(usize, usize, u64)> = None;
+        for i in 0..self.size {
+            if found.len() == count {
+                break;
+            }
+            let bit = (self.hint + i) % self.size;
+            let (block_offset, bitmap_idx, alloc_size) = decomposition(bit);
+            let bits64 = match word {
+                Some((offset, idx, bits64)) if (offset, idx) == (block_offset, bitmap_idx) => {
+                    bits64
+                }
+                _ => {
+                    let bits64 = get_block_cache(
+                        self.start_block_id + block_offset,
+                        Arc::clone(block_device),
+                    )?
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| bitmap_block[bitmap_idx])?;
+                    word = Some((block_offset, bitmap_idx, bits64));
+                    bits64
+                }
+            };
+            if bits64 & (1u64 << alloc_size) == 0 {
+                found.push(bit);
+            }
+        }
+        Ok((found.len() == count).then_some(found))
+    }
+
+    /// 回收一个位
+    ///
+    /// 分配时总是查找空闲的位, 因此回收的顺序不需要和分配的顺序一致;
+    /// 回收一个没有被分配的位说明磁盘上的数据已经损坏, 返回EfsError::Corrupted
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<()> {
+        if bit >= self.size {
+            return Err(EfsError::Corrupted);
         }
-        None
-    }
-
-    /// 回收一个位图
-    /// 
-    /// 
-    /// 感觉不太对?
-    /// 000000111111
-    /// 我如果回收倒数第二个1则就变成
-    /// 000000111101
-    /// 此时重新开始分配会出现错误
-    /// 除非回收的顺序是按照分配的顺序回收
-    /// 000000011111
-    /// 就可以
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_offset, bitmap_idx, alloc_size) = decomposition(bit);
-        get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))
+        get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))?
             .lock()
             .modify(0, |bitmap_block: &mut BitmapBlock| {
-                assert!(bitmap_block[bitmap_idx] & (1u64 << alloc_size) > 0);
+                if bitmap_block[bitmap_idx] & (1u64 << alloc_size) == 0 {
+                    return Err(EfsError::Corrupted);
+                }
                 bitmap_block[bitmap_idx] -= 1u64 << alloc_size;
-            });
+                Ok(())
+            })?
+    }
+
+    /// 检查一个位是否已经被分配
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<bool> {
+        let (block_offset, bitmap_idx, alloc_size) = decomposition(bit);
+        get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))?
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bitmap_idx] & (1u64 << alloc_size) > 0
+            })
+    }
+
+    /// 直接设置一个位的分配状态, 用于文件系统检查时修复位图
+    pub fn set_allocated(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        bit: usize,
+        allocated: bool,
+    ) -> Result<()> {
+        let (block_offset, bitmap_idx, alloc_size) = decomposition(bit);
+        get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))?
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                if allocated {
+                    bitmap_block[bitmap_idx] |= 1u64 << alloc_size;
+                } else {
+                    bitmap_block[bitmap_idx] &= !(1u64 << alloc_size);
+                }
+            })
+    }
+
+    /// 统计位图中空闲的位数
+    pub fn count_free(&self, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        let mut free = 0;
+        for block_offset in 0..self.blocks {
+            let start_bit = block_offset * BLOCK_BITS;
+            if start_bit >= self.size {
+                break;
+            }
+            let valid_bits = (self.size - start_bit).min(BLOCK_BITS);
+            free += get_block_cache(self.start_block_id + block_offset, Arc::clone(block_device))?
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    (0..valid_bits)
+                        .filter(|bit| bitmap_block[bit / 64] & (1u64 << (bit % 64)) == 0)
+                        .count()
+                })?;
+        }
+        Ok(free)
     }
 }
 
 /// 返回(位图所在块, 位图所在BitmapBlock的下标, 位图所在BitmapBlock中u64的二进制表示中的下标)
 fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_offset = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    bit %= BLOCK_BITS;
     (block_offset, bit / 64, bit % 64)
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use spin::Mutex;
+
+    use super::Bitmap;
+    use crate::{
+        block_dev::BlockDevice, efs::EasyFileSystem, test_util::create_fs, EfsError, BLOCK_BITS,
+    };
+
+    /// 在从文件系统分配出的一段连续数据块上放置一个测试用的位图
+    ///
+    /// 位图修改过的块没有提交, 文件系统释放时随之从块缓存中丢弃
+    fn scratch_bitmap(
+        efs: &Arc<Mutex<EasyFileSystem>>,
+        blocks: usize,
+        size: usize,
+    ) -> (Bitmap, Arc<dyn BlockDevice>) {
+        let mut fs = efs.lock();
+        let start = fs.alloc_data_blocks(blocks as u32).unwrap()[0];
+        let bitmap = Bitmap::new(start as usize, blocks, size).unwrap();
+        (bitmap, Arc::clone(&fs.block_device))
+    }
+
+    #[test]
+    fn bitmap_larger_than_its_blocks_is_rejected() {
+        assert!(Bitmap::new(0, 2, 2 * BLOCK_BITS).is_ok());
+        assert_eq!(
+            Bitmap::new(0, 2, 2 * BLOCK_BITS + 1).err(),
+            Some(EfsError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn alloc_scans_every_bitmap_block_and_dealloc_reuses_bits() {
+        let (_, efs) = create_fs(4096, 0);
+        // 3个位图块, 最后一个块只有一部分位有效
+        let size = 2 * BLOCK_BITS + 10;
+        let (mut bitmap, block_device) = scratch_bitmap(&efs, 3, size);
+        let bits: Vec<usize> = (0..size)
+            .map(|_| bitmap.alloc(&block_device).unwrap().unwrap())
+            .collect();
+        assert_eq!(bits, (0..size).collect::<Vec<usize>>());
+        assert_eq!(bitmap.alloc(&block_device), Ok(None));
+        assert_eq!(bitmap.count_free(&block_device), Ok(0));
+
+        // 回收的顺序不需要和分配的顺序一致
+        for bit in [BLOCK_BITS + 5, 3, 2 * BLOCK_BITS + 9] {
+            bitmap.dealloc(&block_device, bit).unwrap();
+            assert_eq!(bitmap.is_allocated(&block_device, bit), Ok(false));
+        }
+        assert_eq!(bitmap.count_free(&block_device), Ok(3));
+        let mut reused: Vec<usize> = (0..3)
+            .map(|_| bitmap.alloc(&block_device).unwrap().unwrap())
+            .collect();
+        reused.sort_unstable();
+        assert_eq!(reused, vec![3, BLOCK_BITS + 5, 2 * BLOCK_BITS + 9]);
+        assert_eq!(bitmap.alloc(&block_device), Ok(None));
+
+        // 回收没有分配或超出范围的位说明数据已经损坏
+        bitmap.dealloc(&block_device, 3).unwrap();
+        assert!(bitmap.dealloc(&block_device, 3).is_err());
+        assert!(bitmap.dealloc(&block_device, size).is_err());
+    }
+
+    #[test]
+    fn alloc_contiguous_and_find_free() {
+        let (_, efs) = create_fs(4096, 0);
+        let size = 200;
+        let (mut bitmap, block_device) = scratch_bitmap(&efs, 1, size);
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 60), Ok(Some(0)));
+        // 跨越u64边界的连续分配
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 10), Ok(Some(60)));
+        for bit in 70..size {
+            bitmap.set_allocated(&block_device, bit, true).unwrap();
+        }
+        for bit in [10, 11, 12, 40] {
+            bitmap.dealloc(&block_device, bit).unwrap();
+        }
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 4), Ok(None));
+        // find_free不修改位图
+        assert_eq!(
+            bitmap.find_free(&block_device, 4),
+            Ok(Some(vec![10, 11, 12, 40]))
+        );
+        assert_eq!(bitmap.find_free(&block_device, 5), Ok(None));
+        assert_eq!(bitmap.count_free(&block_device), Ok(4));
+        // 从头再次查找时能找到上一次分配位置之前的空闲位
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 3), Ok(Some(10)));
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 1), Ok(Some(40)));
+        assert_eq!(bitmap.count_free(&block_device), Ok(0));
+        assert_eq!(bitmap.alloc_contiguous(&block_device, 0), Ok(None));
+        assert_eq!(bitmap.alloc_contiguous(&block_device, size + 1), Ok(None));
+    }
+}
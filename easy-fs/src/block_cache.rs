@@ -1,11 +1,24 @@
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::ops::Range;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::{block_dev::BlockDevice, BLOCK_SZ};
+use crate::{block_dev::BlockDevice, crc32::crc32, EfsError, Result, BLOCK_SZ};
 
-/// 内存中同时能够驻留的最大数据块数量
+/// 内存中默认能够同时驻留的最大数据块数量
 const BLOCK_CACHE_SIZE: usize = 16;
+/// 检测到顺序访问时最多预读的块数量
+const READAHEAD_BLOCKS: usize = 8;
+/// 每个校验和表块中可以存放的校验和数量
+pub const CHECKSUMS_PER_BLOCK: usize = BLOCK_SZ / 4;
+
+/// 校验和表块, 由128个块的CRC32组成
+type ChecksumBlock = [u32; CHECKSUMS_PER_BLOCK];
 
 /// 块缓存
 pub struct BlockCache {
@@ -19,15 +32,68 @@ pub struct BlockCache {
     modified: bool,
 }
 
+/// 块缓存管理器, 采用LRU策略进行换出
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    // 按照最近使用的顺序排列的块缓存, 以(设备标识, 块id)作为键
+    queue: VecDeque<(usize, usize, Arc<Mutex<BlockCache>>)>,
+    // 缓存的容量
+    capacity: usize,
+    stats: BlockCacheStats,
+    // 每个开启了预读的设备的预读状态, 以设备标识作为键
+    readahead: BTreeMap<usize, Readahead>,
+    // 每个开启了校验和的设备的校验和区域, 以设备标识作为键
+    checksums: BTreeMap<usize, ChecksumArea>,
+}
+
+/// 一个设备的预读状态
+struct Readahead {
+    // 设备的总块数, 预读不会超过这个范围
+    total_blocks: usize,
+    // 顺序访问时下一个未命中的块id
+    next_block_id: usize,
+}
+
+/// 一个设备的校验和区域
+///
+/// 校验和表以块id作为下标依次记录每个块的CRC32, 块从磁盘载入时进行校验;
+/// 日志区域以及校验和表本身不进行校验
+#[derive(Clone, Copy)]
+struct ChecksumArea {
+    // 校验和表的起始块id
+    table_start: usize,
+    // 不进行校验的块范围
+    skip: (usize, usize),
+}
+
+impl ChecksumArea {
+    /// 块是否受到校验和的保护
+    fn covers(&self, block_id: usize) -> bool {
+        block_id < self.skip.0 || block_id >= self.skip.1
+    }
+
+    /// 返回(块的校验和所在的表块id, 在表块中的下标)
+    fn entry_pos(&self, block_id: usize) -> (usize, usize) {
+        (
+            self.table_start + block_id / CHECKSUMS_PER_BLOCK,
+            block_id % CHECKSUMS_PER_BLOCK,
+        )
+    }
 }
 
 impl BlockCache {
     /// 从磁盘中加载一个块
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Result<Self> {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        block_device.read_block(block_id, &mut cache)?;
+        Ok(Self::from_data(block_id, block_device, cache))
+    }
+
+    /// 使用已经从磁盘中读出的数据创建块缓存
+    pub fn from_data(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        cache: [u8; BLOCK_SZ],
+    ) -> Self {
         Self {
             cache,
             block_id,
@@ -36,116 +102,620 @@ impl BlockCache {
         }
     }
 
-    /// 将缓冲区的内容写入到磁盘
-    pub fn sync(&mut self) {
+    /// 将缓冲区的内容写入到磁盘, 写入失败时缓冲区仍然被看作修改过
+    pub fn sync(&mut self) -> Result<()> {
         if self.modified {
+            self.block_device.write_block(self.block_id, &self.cache)?;
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
         }
+        Ok(())
+    }
+
+    /// 缓冲区载入内存后是否被修改过
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// 缓冲区当前内容的CRC32校验和
+    pub fn checksum(&self) -> u32 {
+        crc32(&self.cache)
     }
 
     /// get_ref的闭包封装
-    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
-        f(self.get_ref(offset))
+    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> Result<V> {
+        Ok(f(self.get_ref(offset)?))
     }
 
     /// get_mut的闭包封装
-    pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
-        f(self.get_mut(offset))
+    pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> Result<V> {
+        Ok(f(self.get_mut(offset)?))
     }
 
     /// 从指定偏移量中获取指定类型的对象引用
-    pub fn get_ref<T>(&self, offset: usize) -> &T
+    ///
+    /// 对象超出块的范围时返回EfsError::InvalidArgument
+    pub fn get_ref<T>(&self, offset: usize) -> Result<&T>
     where
         T: Sized,
     {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size < BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
+        let addr = self.addr_of_offset::<T>(offset)?;
         // 将addr转为T类型指针
         // 将指针转为对象
         // 取对象引用
-        unsafe { &*(addr as *const T) }
+        Ok(unsafe { &*(addr as *const T) })
     }
 
     /// 从指定偏移量中获取指定类型的对象可变引用
-    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T
+    ///
+    /// 对象超出块的范围时返回EfsError::InvalidArgument
+    pub fn get_mut<T>(&mut self, offset: usize) -> Result<&mut T>
     where
         T: Sized,
     {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size < BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
-        unsafe { &mut *(addr as *mut T) }
+        let addr = self.addr_of_offset::<T>(offset)?;
+        self.modified = true;
+        Ok(unsafe { &mut *(addr as *mut T) })
     }
 }
 
 impl BlockCache {
-    /// 获取指定偏移量所在的数据地址指针
-    fn addr_of_offset(&self, offset: usize) -> usize {
-        if offset <= BLOCK_SZ {
-            panic!("Block offset {} out of BLOCK_SZ: {}", offset, BLOCK_SZ)
+    /// 获取指定偏移量处T类型对象的地址, 检查对象是否完全位于块内
+    fn addr_of_offset<T>(&self, offset: usize) -> Result<usize> {
+        if offset + core::mem::size_of::<T>() > BLOCK_SZ {
+            return Err(EfsError::InvalidArgument);
         }
-        &self.cache[offset] as *const _ as usize
+        Ok(self.cache.as_ptr() as usize + offset)
     }
 }
 
-impl Drop for BlockCache {
-    fn drop(&mut self) {
-        self.sync();
-    }
+/// 块缓存的统计信息
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockCacheStats {
+    // 命中次数
+    pub hits: usize,
+    // 未命中, 需要从磁盘读取的次数
+    pub misses: usize,
+    // 被换出的块数量
+    pub evictions: usize,
+    // 所有块都在使用中, 只能暂时超出容量的次数
+    pub overcommits: usize,
+    // 通过预读载入的块数量
+    pub readahead_blocks: usize,
+    // 载入时校验和不一致的次数
+    pub checksum_errors: usize,
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
-        Self {
+    /// 容量为0时返回EfsError::InvalidArgument
+    pub fn new(capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            return Err(EfsError::InvalidArgument);
+        }
+        Ok(Self {
             queue: VecDeque::new(),
+            capacity,
+            stats: BlockCacheStats::default(),
+            readahead: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+        })
+    }
+
+    /// 为一个设备开启顺序预读
+    ///
+    /// 需要给出设备的总块数, 以免预读到设备末尾之外的块
+    pub fn enable_readahead(&mut self, block_device: &Arc<dyn BlockDevice>, total_blocks: usize) {
+        self.readahead.insert(
+            device_id(block_device),
+            Readahead {
+                total_blocks,
+                next_block_id: usize::MAX,
+            },
+        );
+    }
+
+    /// 为一个设备开启校验和
+    ///
+    /// 校验和表从table_start开始, skip范围内的块不进行校验;
+    /// 开启之后被修改过的块在写回磁盘之前会先更新它在校验和表中的校验和
+    pub fn enable_checksums(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        table_start: usize,
+        skip: Range<usize>,
+    ) {
+        self.checksums.insert(
+            device_id(block_device),
+            ChecksumArea {
+                table_start,
+                skip: (skip.start, skip.end),
+            },
+        );
+    }
+
+    /// 检查从磁盘载入的块是否和校验和表中记录的一致
+    fn verify(&mut self, block_device: &Arc<dyn BlockDevice>, block: &BlockCache) -> Result<bool> {
+        let area = match self.checksums.get(&device_id(block_device)) {
+            Some(area) if area.covers(block.block_id) => *area,
+            _ => return Ok(true),
+        };
+        let (table_block_id, idx) = area.entry_pos(block.block_id);
+        let recorded = self
+            .get_table_block(table_block_id, block_device)?
+            .lock()
+            .read(0, |table: &ChecksumBlock| table[idx])?;
+        Ok(recorded == block.checksum())
+    }
+
+    /// 获取校验和表块, 表块本身不需要校验, 也不参与预读
+    fn get_table_block(
+        &mut self,
+        block_id: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>> {
+        let device = device_id(block_device);
+        if let Some(block) = self.lookup(device, block_id) {
+            return Ok(block);
         }
+        self.stats.misses += 1;
+        let block = BlockCache::new(block_id, Arc::clone(block_device))?;
+        Ok(self.insert(device, block))
     }
 
-    pub fn get_block_cache(
+    /// 将块的校验和写入校验和表块, 校验和没有变化时不修改表块
+    fn write_checksum(
+        table_block: &Arc<Mutex<BlockCache>>,
+        idx: usize,
+        checksum: u32,
+    ) -> Result<()> {
+        let mut table_block = table_block.lock();
+        if table_block.read(0, |table: &ChecksumBlock| table[idx])? != checksum {
+            table_block.modify(0, |table: &mut ChecksumBlock| table[idx] = checksum)?;
+        }
+        Ok(())
+    }
+
+    /// 将被修改过的块的校验和写入校验和表, dirty中的每一项为(块id, 校验和)
+    fn write_checksums(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        dirty: &[(usize, u32)],
+    ) -> Result<()> {
+        let area = match self.checksums.get(&device_id(block_device)) {
+            Some(area) => *area,
+            None => return Ok(()),
+        };
+        for &(block_id, checksum) in dirty.iter().filter(|(id, _)| area.covers(*id)) {
+            let (table_block_id, idx) = area.entry_pos(block_id);
+            let table_block = self.get_table_block(table_block_id, block_device)?;
+            Self::write_checksum(&table_block, idx, checksum)?;
+        }
+        Ok(())
+    }
+
+    /// 计算未命中block_id时需要额外预读的块数量
+    ///
+    /// 只有未命中的块恰好是上一次未命中(或预读)的下一个块时才认为是顺序访问
+    fn readahead_window(&mut self, device: usize, block_id: usize) -> usize {
+        let max_window = READAHEAD_BLOCKS.min(self.capacity / 2);
+        let state = match self.readahead.get_mut(&device) {
+            Some(state) => state,
+            None => return 0,
+        };
+        let window = if state.next_block_id == block_id {
+            max_window.min(state.total_blocks.saturating_sub(block_id + 1))
+        } else {
+            0
+        };
+        state.next_block_id = block_id + 1 + window;
+        // 遇到已经在缓存中的块就停止预读
+        (1..=window)
+            .take_while(|i| {
+                !self
+                    .queue
+                    .iter()
+                    .any(|(dev, id, _)| *dev == device && *id == block_id + i)
+            })
+            .count()
+    }
+
+    /// 为新的块腾出空间, 没有可以换出的块时返回false
+    fn make_room(&mut self) -> bool {
+        while self.queue.len() >= self.capacity {
+            if !self.evict() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 修改缓存的容量, 超出新容量的块会在之后的访问中被逐渐换出
+    ///
+    /// 容量为0时返回EfsError::InvalidArgument
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<()> {
+        if capacity == 0 {
+            return Err(EfsError::InvalidArgument);
+        }
+        self.capacity = capacity;
+        while self.queue.len() > self.capacity && self.evict() {}
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        self.stats
+    }
+
+    /// 获取一个内容全部为0的块缓存
+    ///
+    /// 块不在缓存中时不需要从磁盘载入, 因此也不会进行校验, 用于回收或者格式化块
+    pub fn get_zeroed_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some((_, block)) = self.queue.iter().find(|(id, _)| block_id.eq(id)) {
-            return Arc::clone(block);
-        }
-
-        if self.queue.len() >= BLOCK_CACHE_SIZE {
-            // 由于外部可能还在使用块，因此需要查询到强引用为1的数据块，并将其移除
-            // 强引用为1：没有其他部分使用到这个块
-            if let Some((idx, _)) = self
-                .queue
-                .iter()
-                .enumerate()
-                .find(|(_, (_, block))| Arc::strong_count(block) == 1)
-            {
-                self.queue.drain(idx..=idx);
-            } else {
-                panic!("Run out of BlockCache!");
-            }
+        let device = device_id(&block_device);
+        let block = match self.lookup(device, block_id) {
+            Some(block) => block,
+            None => self.insert(
+                device,
+                BlockCache::from_data(block_id, block_device, [0u8; BLOCK_SZ]),
+            ),
+        };
+        block.lock().cache.fill(0);
+        block.lock().modified = true;
+        block
+    }
+
+    /// 在缓存中查找块, 命中时把块移到队尾
+    fn lookup(&mut self, device: usize, block_id: usize) -> Option<Arc<Mutex<BlockCache>>> {
+        let idx = self
+            .queue
+            .iter()
+            .position(|(dev, id, _)| *dev == device && *id == block_id)?;
+        self.stats.hits += 1;
+        let entry = self.queue.remove(idx).unwrap();
+        let block = Arc::clone(&entry.2);
+        self.queue.push_back(entry);
+        Some(block)
+    }
+
+    /// 将新载入的块放到队尾
+    fn insert(&mut self, device: usize, block: BlockCache) -> Arc<Mutex<BlockCache>> {
+        // 所有的块都在使用中时不再panic, 而是暂时超出容量,
+        // 等这些块被释放之后再通过后续的换出恢复到容量以内
+        if !self.make_room() {
+            self.stats.overcommits += 1;
         }
-        let block = Arc::new(Mutex::new(BlockCache::new(
-            block_id,
-            Arc::clone(&block_device),
-        )));
-        self.queue.push_back((block_id, Arc::clone(&block)));
+        let block_id = block.block_id;
+        let block = Arc::new(Mutex::new(block));
+        self.queue.push_back((device, block_id, Arc::clone(&block)));
         block
     }
+
+    /// 获取块缓存
+    ///
+    /// 队列按照最近使用的顺序排列, 队首是最久没有被使用的块, 每次命中都会把块移到队尾;
+    /// 块从磁盘载入时的校验和与校验和表不一致时返回EfsError::Corrupted,
+    /// 校验失败的块不会进入缓存, 下一次访问时会重新从磁盘载入
+    pub fn get_block_cache(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>> {
+        let device = device_id(&block_device);
+        if let Some(block) = self.lookup(device, block_id) {
+            return Ok(block);
+        }
+
+        self.stats.misses += 1;
+        let window = self.readahead_window(device, block_id);
+        let block = if window == 0 {
+            let block = BlockCache::new(block_id, Arc::clone(&block_device))?;
+            if !self.verify(&block_device, &block)? {
+                self.stats.checksum_errors += 1;
+                return Err(EfsError::Corrupted);
+            }
+            block
+        } else {
+            // 一次读出未命中的块以及之后的window个块
+            let mut buf = vec![0u8; (window + 1) * BLOCK_SZ];
+            block_device.read_blocks(block_id, &mut buf)?;
+            let mut blocks = buf.chunks(BLOCK_SZ).map(|data| {
+                let mut cache = [0u8; BLOCK_SZ];
+                cache.copy_from_slice(data);
+                cache
+            });
+            let block =
+                BlockCache::from_data(block_id, Arc::clone(&block_device), blocks.next().unwrap());
+            if !self.verify(&block_device, &block)? {
+                self.stats.checksum_errors += 1;
+                return Err(EfsError::Corrupted);
+            }
+            // 预读的块不会让缓存超出容量, 遇到校验失败的块就停止预读, 等真正访问它时再报告错误
+            for (i, cache) in blocks.enumerate() {
+                let readahead_id = block_id + 1 + i;
+                let readahead_block =
+                    BlockCache::from_data(readahead_id, Arc::clone(&block_device), cache);
+                if !self.make_room() || self.verify(&block_device, &readahead_block) != Ok(true) {
+                    break;
+                }
+                self.queue
+                    .push_back((device, readahead_id, Arc::new(Mutex::new(readahead_block))));
+                self.stats.readahead_blocks += 1;
+            }
+            block
+        };
+        Ok(self.insert(device, block))
+    }
+
+    /// 换出最久没有被使用的一个块, 没有可以换出的块时返回false
+    ///
+    /// 由于外部可能还在使用块，因此需要查询到强引用为1的数据块，并将其移除
+    /// 强引用为1：没有其他部分使用到这个块
+    /// 被修改过的块只能通过日志提交写回, 不会被换出, 缓存因此暂时超出容量,
+    /// 提交之后这些块成为干净的块, 再通过之后的换出恢复到容量以内
+    fn evict(&mut self) -> bool {
+        match self
+            .queue
+            .iter()
+            .position(|(_, _, block)| Arc::strong_count(block) == 1 && !block.lock().is_modified())
+        {
+            Some(idx) => {
+                self.queue.remove(idx);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 属于某个块设备的所有块缓存
+    ///
+    /// 只复制引用, 不会锁住其中的块: 其他线程可能在持有某个块的锁时请求管理器,
+    /// 持有管理器的锁再去锁块会造成死锁, 因此调用者需要在释放管理器之后再锁住这些块
+    fn device_blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<(usize, Arc<Mutex<BlockCache>>)> {
+        let device = device_id(block_device);
+        self.queue
+            .iter()
+            .filter(|(dev, _, _)| *dev == device)
+            .map(|(_, block_id, cache)| (*block_id, Arc::clone(cache)))
+            .collect()
+    }
+
+    /// 移除属于某个块设备的没有被使用的被修改过的块
+    ///
+    /// 只有强引用为1的块才会被锁住, 它们不可能被其他线程持有
+    fn remove_unused_dirty(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        let device = device_id(block_device);
+        self.queue.retain(|(dev, _, block)| {
+            *dev != device || Arc::strong_count(block) > 1 || !block.lock().is_modified()
+        });
+    }
+
+    /// 将属于某个块设备的没有被使用的块从缓存中移除, 并清除它的预读和校验和状态
+    fn forget_device(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        let device = device_id(block_device);
+        self.queue
+            .retain(|(dev, _, block)| *dev != device || Arc::strong_count(block) > 1);
+        self.readahead.remove(&device);
+        self.checksums.remove(&device);
+    }
 }
 
 lazy_static! {
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
-        Mutex::new(BlockCacheManager::new());
+        Mutex::new(BlockCacheManager::new(BLOCK_CACHE_SIZE).expect("BLOCK_CACHE_SIZE不能为0"));
 }
 
 /// 给其他模块进行调用的获取块的接口
+///
+/// 块的校验和与校验和表不一致时返回EfsError::Corrupted
 pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
-) -> Arc<Mutex<BlockCache>> {
+) -> Result<Arc<Mutex<BlockCache>>> {
     BLOCK_CACHE_MANAGER
         .lock()
         .get_block_cache(block_id, block_device)
 }
+
+/// 获取一个内容全部为0的块, 块不在缓存中时不会从磁盘载入
+pub fn get_zeroed_block_cache(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>,
+) -> Arc<Mutex<BlockCache>> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .get_zeroed_block_cache(block_id, block_device)
+}
+
+/// 获取块缓存的统计信息
+pub fn block_cache_stats() -> BlockCacheStats {
+    BLOCK_CACHE_MANAGER.lock().stats()
+}
+
+/// 修改块缓存的容量
+pub fn set_block_cache_capacity(capacity: usize) -> Result<()> {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity)
+}
+
+/// 为一个设备开启顺序预读
+pub fn block_cache_enable_readahead(block_device: &Arc<dyn BlockDevice>, total_blocks: usize) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .enable_readahead(block_device, total_blocks);
+}
+
+/// 为一个设备开启校验和
+pub fn block_cache_enable_checksums(
+    block_device: &Arc<dyn BlockDevice>,
+    table_start: usize,
+    skip: Range<usize>,
+) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .enable_checksums(block_device, table_start, skip);
+}
+
+/// 为属于某个块设备的被修改过的块更新校验和表
+///
+/// 在日志提交以及写回所有块之前调用, 让块和它的校验和一起写入磁盘;
+/// 在释放管理器的锁之后才计算块的校验和
+pub fn block_cache_update_checksums(block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+    let blocks = {
+        let manager = BLOCK_CACHE_MANAGER.lock();
+        if !manager.checksums.contains_key(&device_id(block_device)) {
+            return Ok(());
+        }
+        manager.device_blocks(block_device)
+    };
+    let dirty: Vec<(usize, u32)> = blocks
+        .iter()
+        .filter_map(|(block_id, cache)| {
+            let cache = cache.lock();
+            cache.is_modified().then(|| (*block_id, cache.checksum()))
+        })
+        .collect();
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .write_checksums(block_device, &dirty)
+}
+
+/// 将属于某个块设备的被修改过的块写回磁盘
+///
+/// 写回绕过了日志, 只在创建文件系统时使用, 此时还没有需要保护的内容;
+/// 某个块写回失败时仍然会继续写回其余的块, 最后返回遇到的第一个错误
+pub fn block_cache_sync_device(block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+    let mut result = block_cache_update_checksums(block_device);
+    let blocks = BLOCK_CACHE_MANAGER.lock().device_blocks(block_device);
+    for (_, cache) in blocks {
+        result = result.and(cache.lock().sync());
+    }
+    result
+}
+
+/// 丢弃属于某个块设备的被修改过但还没有提交的块
+///
+/// 没有被使用的块直接从缓存中移除, 仍在被使用的块重新从磁盘载入;
+/// 某个块载入失败时仍然会继续处理其余的块, 最后返回遇到的第一个错误
+pub fn block_cache_discard_device(block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+    let blocks = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        manager.remove_unused_dirty(block_device);
+        manager.device_blocks(block_device)
+    };
+    let mut result = Ok(());
+    for (block_id, cache) in blocks {
+        let mut cache = cache.lock();
+        if cache.is_modified() {
+            let reloaded = block_device.read_block(block_id, &mut cache.cache);
+            if reloaded.is_ok() {
+                cache.modified = false;
+            }
+            result = result.and(reloaded);
+        }
+    }
+    result
+}
+
+/// 丢弃属于某个块设备的还没有提交的修改, 并将它的块从缓存中移除, 用于卸载设备
+///
+/// 仍在被使用的块无法移除
+pub fn block_cache_invalidate_device(block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+    let result = block_cache_discard_device(block_device);
+    BLOCK_CACHE_MANAGER.lock().forget_device(block_device);
+    result
+}
+
+/// 获取属于某个块设备的被修改过但还没有写回磁盘的块缓存
+pub fn block_cache_dirty_list(
+    block_device: &Arc<dyn BlockDevice>,
+) -> Vec<(usize, Arc<Mutex<BlockCache>>)> {
+    let blocks = BLOCK_CACHE_MANAGER.lock().device_blocks(block_device);
+    blocks
+        .into_iter()
+        .filter(|(_, cache)| cache.lock().is_modified())
+        .collect()
+}
+
+/// 块设备的标识
+///
+/// 块缓存中持有设备的引用, 因此缓存中还有某个设备的块时, 它的地址不会被其他设备复用;
+/// 预读和校验和状态不持有设备, 由EasyFileSystem开启, 并在文件系统被释放时和设备的块一起清除
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use super::{get_block_cache, BLOCK_CACHE_SIZE};
+    use crate::{
+        block_dev::BlockDevice,
+        efs::EasyFileSystem,
+        fsck::fsck,
+        layout::FEATURE_CHECKSUMS,
+        test_util::{create_fs, MemDevice},
+        vfs::Inode,
+        BLOCK_SZ,
+    };
+
+    #[test]
+    fn dirty_blocks_are_not_written_back_on_eviction() {
+        let (device, efs) = create_fs(4096, FEATURE_CHECKSUMS);
+        let block_device = Arc::clone(&efs.lock().block_device);
+        let start = efs.lock().get_data_block_id(0) as usize;
+        let dirty = start..start + BLOCK_CACHE_SIZE * 2;
+        device.start_recording();
+        for block_id in dirty.clone() {
+            get_block_cache(block_id, Arc::clone(&block_device))
+                .unwrap()
+                .lock()
+                .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(7))
+                .unwrap();
+        }
+        // 读取更多的块让缓存需要换出, 被修改过的块只能留在缓存中
+        for block_id in dirty.end..dirty.end + BLOCK_CACHE_SIZE * 2 {
+            get_block_cache(block_id, Arc::clone(&block_device)).unwrap();
+        }
+        assert!(device.take_writes().is_empty());
+
+        // 提交之后块和它们的校验和一起写入磁盘
+        efs.lock().commit().unwrap();
+        let reopened: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(device.image()));
+        let efs = EasyFileSystem::open(Arc::clone(&reopened)).unwrap();
+        assert!(fsck(&efs, false).unwrap().bad_checksums.is_empty());
+        let mut data = [0u8; BLOCK_SZ];
+        for block_id in dirty {
+            reopened.read_block(block_id, &mut data).unwrap();
+            assert_eq!(data, [7u8; BLOCK_SZ]);
+        }
+    }
+
+    #[test]
+    fn sequential_reads_are_prefetched() {
+        let (device, efs) = create_fs(4096, 0);
+        let data: Vec<u8> = (0..64 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+        Inode::root_inode(&efs)
+            .create("file")
+            .unwrap()
+            .write_at(0, &data)
+            .unwrap();
+        let device = Arc::new(MemDevice::from_image(device.image()));
+        let efs = EasyFileSystem::open(device.clone()).unwrap();
+        let file = Inode::root_inode(&efs).find("file").unwrap();
+
+        // 逐块顺序读取, 之后的块通过一次读取多个块的请求预先载入
+        let requests = device.read_requests();
+        let mut buf = vec![0u8; data.len()];
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            assert_eq!(file.read_at(i * BLOCK_SZ, block), Ok(BLOCK_SZ));
+        }
+        assert_eq!(buf, data);
+        let requests = device.read_requests() - requests;
+        assert!(requests <= 64 / 4, "{} read requests", requests);
+    }
+}
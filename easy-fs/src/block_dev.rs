@@ -1,10 +1,32 @@
 use core::any::Any;
 
+use crate::{Result, BLOCK_SZ};
+
 /// 块设备接口
-/// 
+///
 /// 用于对块进行读写,块缓存层会调用这两个方法，进行块缓存的管理
-/// easy-fs本身并不会实现这两个方法.由具体的块设备驱动来实现
+/// easy-fs本身并不会实现这两个方法.由具体的块设备驱动来实现,
+/// 读写失败时返回EfsError::IoError
 pub trait BlockDevice: Send + Sync + Any {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()>;
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()>;
+
+    /// 从start_block_id开始连续读取多个块, buf的长度必须是块大小的整数倍
+    ///
+    /// 块缓存在预读时会调用这个方法, 默认逐块调用read_block,
+    /// 支持一次请求读取多个块的设备(如virtio)可以重写它以减少请求次数
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) -> Result<()> {
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(start_block_id + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// 等待之前写入的块真正落盘
+    ///
+    /// 日志在写入提交块之前和之后各调用一次, 保证提交块不会先于事务中的块到达磁盘,
+    /// 也不会晚于之后写回原位置的块; 没有写缓存的设备可以使用默认的空实现
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
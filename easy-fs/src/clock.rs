@@ -0,0 +1,20 @@
+use spin::Mutex;
+
+/// 获取当前时间的函数, 返回自UNIX纪元以来的秒数
+///
+/// easy-fs不依赖具体的平台, 由使用者通过set_clock提供, 没有提供时时间总是为0
+static CLOCK: Mutex<fn() -> u32> = Mutex::new(no_clock);
+
+fn no_clock() -> u32 {
+    0
+}
+
+/// 设置文件系统用于记录时间戳的时钟
+pub fn set_clock(clock: fn() -> u32) {
+    *CLOCK.lock() = clock;
+}
+
+/// 当前时间
+pub(crate) fn now() -> u32 {
+    (CLOCK.lock())()
+}
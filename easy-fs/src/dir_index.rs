@@ -0,0 +1,167 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    layout::{DirEntry, DIRENT_SZ},
+    BLOCK_SZ,
+};
+
+/// 每个目录块中的目录项数量
+pub const DIRENTS_PER_BLOCK: usize = BLOCK_SZ / DIRENT_SZ;
+/// 线性排列的目录超过这么多个块时改为哈希目录
+pub const DIR_INDEX_THRESHOLD_BLOCKS: usize = 4;
+/// 在哈希目录中查找一个文件名时最多检查的块数
+pub const DIR_INDEX_PROBE_BLOCKS: u32 = 4;
+
+/// 文件名的哈希值, 采用32位的FNV-1a
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// 在有buckets个块的哈希目录中, name所在的目录项可能位于的块, 按照检查的顺序排列
+pub fn probe_blocks(name: &str, buckets: u32) -> impl Iterator<Item = u32> {
+    let home = name_hash(name) % buckets;
+    (0..DIR_INDEX_PROBE_BLOCKS.min(buckets)).map(move |i| (home + i) % buckets)
+}
+
+/// 容纳count个目录项的哈希目录最初的块数, 每个块平均留出四分之一的空位
+pub fn initial_buckets(count: usize) -> u32 {
+    count.div_ceil(DIRENTS_PER_BLOCK * 3 / 4).max(1) as u32
+}
+
+/// 有buckets个块的目录最多需要分配的块数, 包括索引块或者extent块
+pub fn blocks_needed(buckets: u32) -> usize {
+    buckets as usize + (buckets as usize).div_ceil(BLOCK_SZ / 4) + 1
+}
+
+/// 按照哈希目录的格式排列所有的目录项, 返回目录的块数以及目录的全部内容
+///
+/// 块数至少为min_buckets, 有目录项在它的探测范围内找不到空位时块数加倍之后重新排列
+pub fn build(entries: &[DirEntry], min_buckets: u32) -> (u32, Vec<u8>) {
+    let mut buckets = initial_buckets(entries.len()).max(min_buckets);
+    'retry: loop {
+        let mut data = vec![0u8; buckets as usize * BLOCK_SZ];
+        let mut used = vec![0usize; buckets as usize];
+        for dirent in entries {
+            let block = match probe_blocks(dirent.name(), buckets)
+                .find(|&block| used[block as usize] < DIRENTS_PER_BLOCK)
+            {
+                Some(block) => block as usize,
+                None => {
+                    buckets *= 2;
+                    continue 'retry;
+                }
+            };
+            let offset = block * BLOCK_SZ + used[block] * DIRENT_SZ;
+            data[offset..offset + DIRENT_SZ].copy_from_slice(dirent.as_bytes());
+            used[block] += 1;
+        }
+        return (buckets, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, sync::Arc, vec::Vec};
+
+    use super::*;
+    use crate::{
+        block_dev::BlockDevice,
+        efs::EasyFileSystem,
+        fsck::fsck,
+        layout::FEATURE_DIR_INDEX,
+        test_util::{create_fs, MemDevice},
+        vfs::Inode,
+    };
+
+    /// 在build排列出的目录内容中查找name, 返回它所在的块
+    fn lookup(data: &[u8], buckets: u32, name: &str) -> Option<u32> {
+        probe_blocks(name, buckets).find(|&block| {
+            let block = &data[block as usize * BLOCK_SZ..(block as usize + 1) * BLOCK_SZ];
+            block
+                .chunks(DIRENT_SZ)
+                .any(|dirent| dirent.starts_with(name.as_bytes()) && dirent[name.len()] == 0)
+        })
+    }
+
+    #[test]
+    fn name_hash_is_fnv1a() {
+        assert_eq!(name_hash(""), 0x811c9dc5);
+        assert_eq!(name_hash("a"), 0xe40c292c);
+        assert_eq!(name_hash("foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn build_places_every_entry_in_its_probe_range() {
+        let entries: Vec<DirEntry> = (0..1000)
+            .map(|i| DirEntry::new(&format!("file{}", i), i + 1).unwrap())
+            .collect();
+        let (buckets, data) = build(&entries, 0);
+        assert!(buckets >= initial_buckets(entries.len()));
+        assert_eq!(data.len(), buckets as usize * BLOCK_SZ);
+        for dirent in entries.iter() {
+            assert!(lookup(&data, buckets, dirent.name()).is_some());
+        }
+        assert!(lookup(&data, buckets, "missing").is_none());
+    }
+
+    #[test]
+    fn full_probe_range_doubles_buckets() {
+        // 哈希到同一个块的目录项超过探测范围的容量时, 块数需要加倍
+        let buckets = 8;
+        let names: Vec<_> = (0..)
+            .map(|i| format!("n{}", i))
+            .filter(|name| name_hash(name).is_multiple_of(buckets))
+            .take(DIRENTS_PER_BLOCK * DIR_INDEX_PROBE_BLOCKS as usize + 1)
+            .collect();
+        let entries: Vec<DirEntry> = names
+            .iter()
+            .map(|name| DirEntry::new(name, 1).unwrap())
+            .collect();
+        let (split, data) = build(&entries, buckets);
+        assert!(split >= buckets * 2);
+        for name in names.iter() {
+            assert!(lookup(&data, split, name).is_some());
+        }
+    }
+
+    #[test]
+    fn crash_during_rebuild_keeps_directory() {
+        let (device, efs) = create_fs(4096, FEATURE_DIR_INDEX);
+        let root = Inode::root_inode(&efs);
+        let count = DIR_INDEX_THRESHOLD_BLOCKS * DIRENTS_PER_BLOCK - 2;
+        for i in 0..count {
+            root.create(&format!("f{}", i)).unwrap();
+        }
+        let image = device.image();
+        device.start_recording();
+        root.create("last").unwrap();
+        let writes = device.take_writes();
+        assert_eq!(root.stat().unwrap().size as usize % BLOCK_SZ, 0);
+
+        let mut created = false;
+        for crash in 0..=writes.len() {
+            let crashed: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(
+                MemDevice::crash_image(&image, &writes, crash),
+            ));
+            let efs = EasyFileSystem::open(Arc::clone(&crashed)).unwrap();
+            let report = fsck(&efs, false).unwrap();
+            assert!(
+                report.is_clean(),
+                "crash after write {}: {:?}",
+                crash,
+                report
+            );
+            let root = Inode::root_inode(&efs);
+            for i in 0..count {
+                root.find(&format!("f{}", i)).unwrap();
+            }
+            // 新的目录项在最后一次事务提交之后出现, 之后不会再消失
+            let found = root.find("last").is_ok();
+            assert!(found || !created);
+            created = found;
+        }
+        assert!(created);
+    }
+}
@@ -0,0 +1,2080 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::ops::Range;
+use spin::Mutex;
+
+use crate::{
+    bitmap::Bitmap,
+    block_cache::{
+        block_cache_dirty_list, block_cache_discard_device, block_cache_enable_checksums,
+        block_cache_enable_readahead, block_cache_invalidate_device, block_cache_sync_device,
+        block_cache_update_checksums, get_block_cache, get_zeroed_block_cache, BlockCache,
+        CHECKSUMS_PER_BLOCK,
+    },
+    block_dev::BlockDevice,
+    clock::now,
+    crc32::crc32,
+    dir_index,
+    journal::Journal,
+    layout::{
+        DirEntry, DiskInode, DiskInodeMeta, DiskInodeType, SuperBlock, CHUNK_BLOCKS,
+        COMPRESSED_CHUNK_SZ, DIRENT_SZ, FEATURE_CHECKSUMS, FEATURE_COMPRESSION, FEATURE_DIR_INDEX,
+        FEATURE_EXTENTS, FEATURE_INLINE_DATA, FEATURE_SNAPSHOTS, INLINE_DATA_LIMIT,
+        INODE_FLAG_COMPRESSED, INODE_FLAG_EXTENTS, INODE_FLAG_INLINE, STATE_RECOUNT,
+    },
+    lz,
+    snapshot::{SnapshotHeader, SnapshotInfo, SnapshotTable, MAX_SNAPSHOTS, SNAPSHOT_NAME_LIMIT},
+    EfsError, Result, BLOCK_BITS, BLOCK_SZ,
+};
+
+/// 磁盘块上的数据
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 日志区域的块数
+const JOURNAL_BLOCKS: u32 = 64;
+/// 创建、删除和回滚快照时每处理这么多个块提交一次, 让每次事务都不会超过日志的容量
+const SNAPSHOT_BATCH_BLOCKS: usize = 32;
+/// 重新计算一段块的校验和时每修改这么多个校验和表块提交一次
+const CHECKSUM_BATCH_TABLE_BLOCKS: usize = 32;
+/// 搬移文件的数据块时每次最多处理这么多个块, 每批作为一次事务提交
+const RELOCATE_BATCH_BLOCKS: u32 = 32;
+/// 创建时校验和表能够覆盖的块数为总块数的这么多倍, 让开启了校验和的文件系统之后可以扩大
+const CHECKSUM_GROWTH_FACTOR: u32 = 8;
+/// 重建哈希目录时每次事务写入的目录块数
+const REBUILD_BATCH_BLOCKS: usize = 32;
+
+/// easy-fs文件系统
+///
+/// 磁盘按照以下顺序划分为七个连续的区域:
+/// 超级块 | 日志区域 | 校验和表 | inode位图 | inode区域 | 数据位图 | 数据区域
+///
+/// 没有开启校验和时校验和表的块数为0;
+/// 存在快照时数据区域中的块可能被多个文件系统树共享, 修改共享的块之前需要先复制一份
+pub struct EasyFileSystem {
+    // 文件系统所在的块设备
+    pub block_device: Arc<dyn BlockDevice>,
+    // inode位图
+    pub inode_bitmap: Bitmap,
+    // 数据位图
+    pub data_bitmap: Bitmap,
+    // inode区域的起始块编号
+    inode_area_start_block: u32,
+    // 数据区域的起始块编号
+    data_area_start_block: u32,
+    // 预写日志
+    journal: Journal,
+    // 超级块中记录的文件系统特性
+    features: u32,
+    // 每个inode占用的字节数, 由超级块中的版本决定
+    inode_size: usize,
+    // 校验和表的块数, 校验和表从日志区域之后开始
+    checksum_blocks: u32,
+    // 快照表所在的块, 没有快照时为0
+    snapshot_table: u32,
+    // 是否以只读方式打开
+    read_only: bool,
+}
+
+impl EasyFileSystem {
+    /// 在块设备上创建并初始化一个easy-fs文件系统
+    ///
+    /// 只需要给出总块数和inode位图的块数, 其余区域的大小会自动计算
+    pub fn create(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        Self::create_with_features(block_device, total_blocks, inode_bitmap_blocks, 0)
+    }
+
+    /// 在块设备上创建一个开启了指定特性的easy-fs文件系统
+    ///
+    /// features为FEATURE_*常量的组合, 例如FEATURE_EXTENTS让新建的inode使用extent记录数据块;
+    /// 总块数不足以放下各个区域时返回EfsError::InvalidArgument
+    pub fn create_with_features(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        features: u32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        // 超级块占用0号块, 之后依次是日志区域、校验和表和inode位图
+        // 校验和表无法在之后扩大, 预留的容量决定了在线扩大文件系统的上限
+        let checksum_blocks = if features & FEATURE_CHECKSUMS != 0 {
+            total_blocks
+                .saturating_mul(CHECKSUM_GROWTH_FACTOR)
+                .div_ceil(CHECKSUMS_PER_BLOCK as u32)
+        } else {
+            0
+        };
+        let inode_bitmap_start_block = 1 + JOURNAL_BLOCKS + checksum_blocks;
+        if inode_bitmap_blocks == 0 || inode_bitmap_blocks as usize > u32::MAX as usize / BLOCK_BITS
+        {
+            return Err(EfsError::InvalidArgument);
+        }
+        let inode_bitmap = Bitmap::new(
+            inode_bitmap_start_block as usize,
+            inode_bitmap_blocks as usize,
+            inode_bitmap_blocks as usize * BLOCK_BITS,
+        )?;
+        let inode_num = inode_bitmap.maximum();
+        let inode_size = core::mem::size_of::<DiskInode>() + core::mem::size_of::<DiskInodeMeta>();
+        let inode_area_blocks = (inode_num * inode_size).div_ceil(BLOCK_SZ) as u32;
+        let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
+        // 数据区域至少需要放下一个数据位图块以及根目录的数据块
+        let data_total_blocks = total_blocks
+            .checked_sub(inode_bitmap_start_block + inode_total_blocks)
+            .filter(|&blocks| blocks >= 2)
+            .ok_or(EfsError::InvalidArgument)?;
+        // 每个数据位图块可以管理4096个数据块, 因此每4097个块中需要一个作为位图
+        let data_bitmap_blocks = data_total_blocks.div_ceil(BLOCK_BITS as u32 + 1);
+        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        let data_bitmap = Bitmap::new(
+            (inode_bitmap_start_block + inode_total_blocks) as usize,
+            data_bitmap_blocks as usize,
+            data_area_blocks as usize,
+        )?;
+        let mut efs = Self {
+            block_device: Arc::clone(&block_device),
+            inode_bitmap,
+            data_bitmap,
+            inode_area_start_block: inode_bitmap_start_block + inode_bitmap_blocks,
+            data_area_start_block: inode_bitmap_start_block
+                + inode_total_blocks
+                + data_bitmap_blocks,
+            journal: Journal::new(1, JOURNAL_BLOCKS as usize),
+            features,
+            inode_size,
+            checksum_blocks,
+            snapshot_table: 0,
+            read_only: false,
+        };
+
+        // 清空所有的块, 直接写入块设备, 不需要让整个磁盘经过块缓存
+        let zero_block = [0u8; BLOCK_SZ];
+        for i in 0..total_blocks {
+            block_device.write_block(i as usize, &zero_block)?;
+        }
+
+        // 所有的块都已经被清零, 之后被修改的块在写回时会更新自己的校验和
+        if checksum_blocks > 0 {
+            let zero_checksum = crc32(&[0u8; BLOCK_SZ]);
+            for i in 0..checksum_blocks {
+                get_zeroed_block_cache(
+                    (1 + JOURNAL_BLOCKS + i) as usize,
+                    Arc::clone(&block_device),
+                )
+                .lock()
+                .modify(0, |table: &mut [u32; CHECKSUMS_PER_BLOCK]| {
+                    table
+                        .iter_mut()
+                        .for_each(|checksum| *checksum = zero_checksum);
+                })?;
+            }
+            efs.enable_checksums();
+        }
+
+        // 写入超级块
+        get_block_cache(0, Arc::clone(&block_device))?
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.initialize(
+                    total_blocks,
+                    JOURNAL_BLOCKS,
+                    inode_bitmap_blocks,
+                    inode_area_blocks,
+                    data_bitmap_blocks,
+                    data_area_blocks,
+                );
+                super_block.features = features;
+                super_block.checksum_blocks = checksum_blocks;
+            })?;
+
+        // 创建根目录, 根目录的inode编号必须为0, 它的父目录就是它自己
+        if efs.alloc_inode()? != 0 {
+            return Err(EfsError::Corrupted);
+        }
+        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
+        get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))?
+            .lock()
+            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(
+                    DiskInodeType::Directory,
+                    efs.new_inode_flags(DiskInodeType::Directory),
+                );
+                efs.init_dir(disk_inode, 0, 0)
+            })??;
+        efs.modify_inode_meta(0, |meta| meta.initialize(0o755, now()))?;
+        block_cache_sync_device(&block_device)?;
+        block_cache_enable_readahead(&block_device, total_blocks as usize);
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+
+    /// 从块设备上打开一个已经存在的easy-fs文件系统
+    ///
+    /// 根据超级块中记录的各个区域大小重新构建位图, 并重放日志中已经提交的事务;
+    /// 超级块不合法时返回EfsError::Corrupted,
+    /// 开启了校验和时还会检查超级块和所有的位图块, 发现损坏的块时同样返回EfsError::Corrupted
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>> {
+        Self::open_with_mode(block_device, false)
+    }
+
+    /// 以只读方式打开一个已经存在的easy-fs文件系统, 不会写入块设备
+    ///
+    /// 日志中已经提交的事务只重放到块缓存中, 需要重新计算的空闲计数同样只在块缓存中更新,
+    /// 因此看到的内容和正常打开时一致; 之后的提交都会返回EfsError::ReadOnly
+    pub fn open_read_only(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>> {
+        Self::open_with_mode(block_device, true)
+    }
+
+    fn open_with_mode(
+        block_device: Arc<dyn BlockDevice>,
+        read_only: bool,
+    ) -> Result<Arc<Mutex<Self>>> {
+        // 先重放日志, 之后读出的超级块才是最后一次提交的事务之后的内容
+        let journal_blocks = get_block_cache(0, Arc::clone(&block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                super_block.is_valid().then_some(super_block.journal_blocks)
+            })?
+            .ok_or(EfsError::Corrupted)?;
+        let mut journal = Journal::new(1, journal_blocks as usize);
+        if read_only {
+            journal.load(&block_device)?;
+        } else {
+            journal.replay(&block_device)?;
+        }
+        let mut total_blocks = 0;
+        let mut efs = get_block_cache(0, Arc::clone(&block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                if !super_block.is_valid() {
+                    return Err(EfsError::Corrupted);
+                }
+                total_blocks = super_block.total_blocks as usize;
+                Self::from_super_block(&block_device, super_block, journal)
+            })??;
+        efs.read_only = read_only;
+        if efs.has_checksums() {
+            // 超级块是在开启校验和之前载入的, 需要重新载入并校验
+            block_cache_invalidate_device(&block_device)?;
+            efs.enable_checksums();
+            if read_only {
+                // 只重放到块缓存中的事务也被清除了
+                efs.journal.load(&block_device)?;
+            }
+            get_block_cache(0, Arc::clone(&block_device))?;
+            for block_id in efs.bitmap_blocks() {
+                get_block_cache(block_id, Arc::clone(&block_device))?;
+            }
+        }
+        efs.recount_if_needed()?;
+        block_cache_enable_readahead(&block_device, total_blocks);
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+
+    /// 上一次在修改空闲计数的过程中崩溃了, 根据位图重新计算
+    ///
+    /// 只读时只在块缓存中更新空闲计数, 不会提交
+    fn recount_if_needed(&mut self) -> Result<()> {
+        let state = get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.state)?;
+        if state & STATE_RECOUNT != 0 {
+            self.recount_free()?;
+            if !self.read_only {
+                self.set_recount_needed(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 根据超级块计算出各个区域的位置
+    fn from_super_block(
+        block_device: &Arc<dyn BlockDevice>,
+        super_block: &SuperBlock,
+        journal: Journal,
+    ) -> Result<Self> {
+        let mut efs = Self {
+            block_device: Arc::clone(block_device),
+            inode_bitmap: Bitmap::new(0, 0, 0)?,
+            data_bitmap: Bitmap::new(0, 0, 0)?,
+            inode_area_start_block: 0,
+            data_area_start_block: 0,
+            journal,
+            features: 0,
+            inode_size: 0,
+            checksum_blocks: 0,
+            snapshot_table: 0,
+            read_only: false,
+        };
+        efs.load_super_block(super_block)?;
+        Ok(efs)
+    }
+
+    /// 根据超级块重新计算各个区域的位置以及特性, 块设备和日志保持不变
+    ///
+    /// 超级块不合法时返回EfsError::Corrupted
+    fn load_super_block(&mut self, super_block: &SuperBlock) -> Result<()> {
+        if !super_block.is_valid() {
+            return Err(EfsError::Corrupted);
+        }
+        let inode_bitmap_start_block = 1 + super_block.journal_blocks + super_block.checksum_blocks;
+        let inode_total_blocks = super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+        self.inode_bitmap = Bitmap::new(
+            inode_bitmap_start_block as usize,
+            super_block.inode_bitmap_blocks as usize,
+            super_block.inode_bitmap_blocks as usize * BLOCK_BITS,
+        )?;
+        self.data_bitmap = Bitmap::new(
+            (inode_bitmap_start_block + inode_total_blocks) as usize,
+            super_block.data_bitmap_blocks as usize,
+            super_block.data_area_blocks as usize,
+        )?;
+        self.inode_area_start_block = inode_bitmap_start_block + super_block.inode_bitmap_blocks;
+        self.data_area_start_block =
+            inode_bitmap_start_block + inode_total_blocks + super_block.data_bitmap_blocks;
+        self.features = super_block.features;
+        self.inode_size = super_block.inode_size();
+        self.checksum_blocks = super_block.checksum_blocks;
+        self.snapshot_table = super_block.snapshot_table;
+        Ok(())
+    }
+
+    /// 放弃最后一次提交之后的所有修改
+    ///
+    /// 块缓存中被修改过的块被丢弃或者重新从磁盘载入, 根据超级块计算出的区域划分和特性也重新载入;
+    /// 操作中途返回错误时调用, 让文件系统回到最后一次提交的事务之后的状态,
+    /// 已经分批提交的部分仍然保留
+    pub fn abort(&mut self) -> Result<()> {
+        block_cache_discard_device(&self.block_device)?;
+        if self.read_only {
+            // 只重放到块缓存中的事务同样被丢弃了
+            self.journal.load(&self.block_device)?;
+        }
+        let block_device = Arc::clone(&self.block_device);
+        let total_blocks = get_block_cache(0, Arc::clone(&block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                self.load_super_block(super_block)?;
+                Ok(super_block.total_blocks as usize)
+            })??;
+        if self.has_checksums() {
+            self.enable_checksums();
+        }
+        if self.read_only {
+            self.recount_if_needed()?;
+        }
+        block_cache_enable_readahead(&block_device, total_blocks);
+        Ok(())
+    }
+
+    /// 执行一个修改文件系统的操作, 返回错误时通过abort放弃还没有提交的修改
+    fn abort_on_error<V>(&mut self, f: impl FnOnce(&mut Self) -> Result<V>) -> Result<V> {
+        let result = f(self);
+        if result.is_err() {
+            self.abort()?;
+        }
+        result
+    }
+
+    /// 是否开启了校验和
+    pub fn has_checksums(&self) -> bool {
+        self.checksum_blocks > 0
+    }
+
+    /// 校验和表的起始块编号, 紧跟在日志区域之后
+    fn checksum_start_block(&self) -> usize {
+        1 + self.journal.blocks()
+    }
+
+    /// 让块缓存在载入块时进行校验, 日志区域和校验和表本身不需要校验
+    fn enable_checksums(&self) {
+        let checksum_start_block = self.checksum_start_block();
+        block_cache_enable_checksums(
+            &self.block_device,
+            checksum_start_block,
+            1..checksum_start_block + self.checksum_blocks as usize,
+        );
+    }
+
+    /// inode位图和数据位图的所有块编号
+    fn bitmap_blocks(&self) -> impl Iterator<Item = usize> {
+        self.inode_bitmap
+            .block_range()
+            .chain(self.data_bitmap.block_range())
+    }
+
+    /// 受校验和保护的所有块编号: 超级块以及校验和表之后的所有块
+    fn checksum_covered_blocks(&self) -> Result<impl Iterator<Item = usize>> {
+        let covered_start = self.checksum_start_block() + self.checksum_blocks as usize;
+        Ok(core::iter::once(0).chain(covered_start..self.total_blocks()?))
+    }
+
+    /// 文件系统的总块数
+    pub fn total_blocks(&self) -> Result<usize> {
+        get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                super_block.total_blocks as usize
+            })
+    }
+
+    /// 查找内容与校验和表不一致的块
+    ///
+    /// 直接从块设备读取, 不经过块缓存, 因此损坏的块不会导致错误;
+    /// 还没有写回的块(例如只读打开时只重放到块缓存中的事务)以块缓存中的内容为准
+    pub fn find_bad_checksums(&self) -> Result<Vec<u32>> {
+        block_cache_update_checksums(&self.block_device)?;
+        let dirty: BTreeMap<usize, Arc<Mutex<BlockCache>>> =
+            block_cache_dirty_list(&self.block_device)
+                .into_iter()
+                .collect();
+        let mut bad: Vec<u32> = Vec::new();
+        let mut data = [0u8; BLOCK_SZ];
+        for block_id in self.checksum_covered_blocks()? {
+            match dirty.get(&block_id) {
+                Some(cache) => cache
+                    .lock()
+                    .read(0, |data_block: &DataBlock| data.copy_from_slice(data_block))?,
+                None => self.block_device.read_block(block_id, &mut data)?,
+            }
+            if crc32(&data) != self.recorded_checksum(block_id)? {
+                bad.push(block_id as u32);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// 校验和表中记录的块的校验和
+    fn recorded_checksum(&self, block_id: usize) -> Result<u32> {
+        get_block_cache(
+            self.checksum_start_block() + block_id / CHECKSUMS_PER_BLOCK,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .read(0, |table: &[u32; CHECKSUMS_PER_BLOCK]| {
+            table[block_id % CHECKSUMS_PER_BLOCK]
+        })
+    }
+
+    /// 以块在磁盘上的当前内容重新计算它的校验和
+    fn fix_checksum(&self, block_id: u32) -> Result<()> {
+        let mut data = [0u8; BLOCK_SZ];
+        self.block_device.read_block(block_id as usize, &mut data)?;
+        let checksum = crc32(&data);
+        get_block_cache(
+            self.checksum_start_block() + block_id as usize / CHECKSUMS_PER_BLOCK,
+            Arc::clone(&self.block_device),
+        )?
+        .lock()
+        .modify(0, |table: &mut [u32; CHECKSUMS_PER_BLOCK]| {
+            table[block_id as usize % CHECKSUMS_PER_BLOCK] = checksum;
+        })
+    }
+
+    /// 接受blocks中每个块在磁盘上的当前内容, 以它重新计算校验和
+    ///
+    /// 损坏的内容同样会被接受, 之后就再也无法发现这些块的损坏, 因此文件系统检查不会自动调用,
+    /// 只应该在确认了块的内容之后显式调用; 块不受校验和保护时返回EfsError::InvalidArgument.
+    /// 分批提交, 调用前需要先提交所有的修改
+    pub fn accept_checksums(&mut self, blocks: &[u32]) -> Result<()> {
+        if !self.has_checksums() {
+            return Err(EfsError::InvalidArgument);
+        }
+        let covered_start = (self.checksum_start_block() + self.checksum_blocks as usize) as u32;
+        let total_blocks = self.total_blocks()? as u32;
+        if blocks
+            .iter()
+            .any(|&block_id| block_id != 0 && !(covered_start..total_blocks).contains(&block_id))
+        {
+            return Err(EfsError::InvalidArgument);
+        }
+        self.abort_on_error(|fs| {
+            for (i, &block_id) in blocks.iter().enumerate() {
+                fs.fix_checksum(block_id)?;
+                if (i + 1) % CHECKSUM_BATCH_TABLE_BLOCKS == 0 {
+                    fs.commit()?;
+                }
+            }
+            fs.commit()
+        })
+    }
+
+    /// 以块在磁盘上的当前内容重新计算range范围内所有块的校验和
+    ///
+    /// 分批提交, 调用前需要先提交所有的修改
+    fn fix_checksums(&mut self, range: Range<u32>) -> Result<()> {
+        let batch = (CHECKSUM_BATCH_TABLE_BLOCKS * CHECKSUMS_PER_BLOCK) as u32;
+        for block_id in range {
+            self.fix_checksum(block_id)?;
+            if (block_id + 1) % batch == 0 {
+                self.commit()?;
+            }
+        }
+        self.commit()
+    }
+
+    /// 以一次事务的形式提交所有被修改过的块
+    ///
+    /// 所有修改文件系统的操作都应该在结束时调用, 调用时不能持有任何块缓存的锁;
+    /// 以只读方式打开时返回EfsError::ReadOnly
+    pub fn commit(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(EfsError::ReadOnly);
+        }
+        self.journal.commit(&self.block_device)
+    }
+
+    /// 是否以只读方式打开
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 根据inode编号获取DiskInode所在的块编号以及块内偏移
+    pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
+        let inodes_per_block = (BLOCK_SZ / self.inode_size) as u32;
+        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
+        (
+            block_id,
+            (inode_id % inodes_per_block) as usize * self.inode_size,
+        )
+    }
+
+    /// inode是否带有权限、所有者以及时间戳, 版本1的镜像中没有这些数据
+    pub fn has_inode_meta(&self) -> bool {
+        self.inode_size > core::mem::size_of::<DiskInode>()
+    }
+
+    /// 对inode的元数据进行只读访问, 镜像中没有元数据时返回None
+    pub fn read_inode_meta<V>(
+        &self,
+        inode_id: u32,
+        f: impl FnOnce(&DiskInodeMeta) -> V,
+    ) -> Result<Option<V>> {
+        if !self.has_inode_meta() {
+            return Ok(None);
+        }
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(block_offset + core::mem::size_of::<DiskInode>(), f)
+            .map(Some)
+    }
+
+    /// 对inode的元数据进行修改, 镜像中没有元数据时返回None
+    ///
+    /// 调用时不能持有这个inode所在块的锁
+    pub fn modify_inode_meta<V>(
+        &self,
+        inode_id: u32,
+        f: impl FnOnce(&mut DiskInodeMeta) -> V,
+    ) -> Result<Option<V>> {
+        if !self.has_inode_meta() {
+            return Ok(None);
+        }
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(block_offset + core::mem::size_of::<DiskInode>(), f)
+            .map(Some)
+    }
+
+    /// 将数据区域内的块编号转换为磁盘上的块编号
+    pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
+        self.data_area_start_block + data_block_id
+    }
+
+    /// 新建的inode使用的标志, 由超级块中的特性以及inode的类型决定
+    ///
+    /// 目录需要按照目录项的偏移进行读写, 它的内容总是保存在数据块中;
+    /// 只有普通文件会被压缩, 压缩的文件不会将内容直接保存在DiskInode中
+    pub fn new_inode_flags(&self, type_: DiskInodeType) -> u16 {
+        let mut flags = 0;
+        if self.features & FEATURE_EXTENTS != 0 {
+            flags |= INODE_FLAG_EXTENTS;
+        }
+        if self.features & FEATURE_COMPRESSION != 0 && type_ == DiskInodeType::File {
+            flags |= INODE_FLAG_COMPRESSED;
+        } else if self.features & FEATURE_INLINE_DATA != 0 && type_ != DiskInodeType::Directory {
+            flags |= INODE_FLAG_INLINE;
+        }
+        flags
+    }
+
+    /// 对超级块进行修改
+    fn modify_super_block<V>(&self, f: impl FnOnce(&mut SuperBlock) -> V) -> Result<V> {
+        get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(0, f)
+    }
+
+    /// 设置超级块中的STATE_RECOUNT状态并立即提交
+    ///
+    /// 需要拆分为多个事务完成的操作在开始前设置, 全部完成后清除,
+    /// 中途崩溃时下一次打开会根据位图重新计算空闲计数
+    pub fn set_recount_needed(&mut self, needed: bool) -> Result<()> {
+        self.modify_super_block(|super_block| {
+            if needed {
+                super_block.state |= STATE_RECOUNT;
+            } else {
+                super_block.state &= !STATE_RECOUNT;
+            }
+        })?;
+        self.commit()
+    }
+
+    /// 空闲的inode数量
+    pub fn free_inodes(&self) -> Result<u32> {
+        get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.free_inodes)
+    }
+
+    /// 空闲的数据块数量
+    pub fn free_data_blocks(&self) -> Result<u32> {
+        get_block_cache(0, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.free_data_blocks)
+    }
+
+    /// 根据位图重新计算超级块中的空闲计数
+    pub fn recount_free(&mut self) -> Result<()> {
+        let free_inodes = self.inode_bitmap.count_free(&self.block_device)? as u32;
+        let free_data_blocks = self.data_bitmap.count_free(&self.block_device)? as u32;
+        self.modify_super_block(|super_block| {
+            super_block.free_inodes = free_inodes;
+            super_block.free_data_blocks = free_data_blocks;
+        })
+    }
+
+    /// 分配一个inode, 返回inode编号, 没有空闲的inode时返回EfsError::NoSpace
+    pub fn alloc_inode(&mut self) -> Result<u32> {
+        let inode_id = self
+            .inode_bitmap
+            .alloc(&self.block_device)?
+            .ok_or(EfsError::NoSpace)? as u32;
+        self.modify_super_block(|super_block| {
+            super_block.free_inodes = super_block.free_inodes.saturating_sub(1)
+        })?;
+        Ok(inode_id)
+    }
+
+    /// 分配一个数据块, 返回的是磁盘上的块编号, 没有空闲的数据块时返回EfsError::NoSpace
+    ///
+    /// 回收的块不会被清零, 分配出的块在块缓存中清零, 和分配在同一次事务中写入磁盘
+    pub fn alloc_data(&mut self) -> Result<u32> {
+        let bit = self
+            .data_bitmap
+            .alloc(&self.block_device)?
+            .ok_or(EfsError::NoSpace)? as u32;
+        self.modify_super_block(|super_block| {
+            super_block.free_data_blocks = super_block.free_data_blocks.saturating_sub(1)
+        })?;
+        let block_id = bit + self.data_area_start_block;
+        get_zeroed_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        Ok(block_id)
+    }
+
+    /// 分配count个数据块
+    ///
+    /// 优先分配一段连续的块, 让大文件的数据在磁盘上尽量连续;
+    /// 空闲空间过于零散时将每次分配的长度减半, 以尽量少的几段连续块完成分配;
+    /// 分配出的块和alloc_data一样会被清零; 空闲的数据块不足时不会分配任何块, 返回EfsError::NoSpace
+    pub fn alloc_data_blocks(&mut self, count: u32) -> Result<Vec<u32>> {
+        if count > self.free_data_blocks()? {
+            return Err(EfsError::NoSpace);
+        }
+        let mut v: Vec<u32> = Vec::with_capacity(count as usize);
+        let mut run = count;
+        while (v.len() as u32) < count {
+            run = run.min(count - v.len() as u32);
+            match self
+                .data_bitmap
+                .alloc_contiguous(&self.block_device, run as usize)?
+            {
+                Some(start) => {
+                    self.modify_super_block(|super_block| {
+                        super_block.free_data_blocks =
+                            super_block.free_data_blocks.saturating_sub(run)
+                    })?;
+                    let start = start as u32 + self.data_area_start_block;
+                    for block_id in start..start + run {
+                        get_zeroed_block_cache(block_id as usize, Arc::clone(&self.block_device));
+                    }
+                    v.extend(start..start + run);
+                }
+                None if run > 1 => run /= 2,
+                None => {
+                    // 空闲计数和位图不一致, 归还已经分配的块
+                    for block_id in v {
+                        self.dealloc_data(block_id)?;
+                    }
+                    return Err(EfsError::NoSpace);
+                }
+            }
+        }
+        Ok(v)
+    }
+
+    /// 为DiskInode中[offset, offset + len)范围涉及的空洞分配数据块
+    ///
+    /// 范围超出文件末尾时会先扩大文件, 中间跳过的部分保持为空洞, 不会分配数据块;
+    /// 内容直接保存在DiskInode中并且范围超过INLINE_DATA_LIMIT时先将内容移到数据块中;
+    /// 范围内已经分配的数据块被快照共享时先复制一份, 之后可以直接写入;
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时文件的大小保持不变
+    pub fn fill_holes(
+        &mut self,
+        disk_inode: &mut DiskInode,
+        offset: usize,
+        len: usize,
+    ) -> Result<()> {
+        let end = offset + len;
+        if end > disk_inode.max_size() {
+            return Err(EfsError::FileTooLarge);
+        }
+        self.expand_inline(disk_inode, end)?;
+        self.unshare(disk_inode, offset, len)?;
+        self.alloc_holes(disk_inode, offset, len)
+    }
+
+    /// 为[offset, offset + len)范围涉及的空洞分配数据块, 不处理直接保存的内容以及共享的块
+    fn alloc_holes(&mut self, disk_inode: &mut DiskInode, offset: usize, len: usize) -> Result<()> {
+        let end = offset + len;
+        let old_size = disk_inode.size;
+        if end > disk_inode.size as usize {
+            disk_inode.size = end as u32;
+        }
+        if len == 0 || disk_inode.is_inline() {
+            return Ok(());
+        }
+        let start_block = (offset / BLOCK_SZ) as u32;
+        let end_block = end.div_ceil(BLOCK_SZ) as u32;
+        let block_device = Arc::clone(&self.block_device);
+        let new_blocks = match disk_inode
+            .count_holes(start_block, end_block, &block_device)
+            .and_then(|holes| self.alloc_data_blocks(holes))
+        {
+            Ok(new_blocks) => new_blocks,
+            Err(err) => {
+                disk_inode.size = old_size;
+                return Err(err);
+            }
+        };
+        let freed = disk_inode.fill_holes(
+            start_block,
+            end_block,
+            new_blocks,
+            &mut || self.alloc_data(),
+            &block_device,
+        )?;
+        for block_id in freed {
+            self.dealloc_data(block_id)?;
+        }
+        Ok(())
+    }
+
+    /// 文件需要扩大到new_size字节并且超过INLINE_DATA_LIMIT时,
+    /// 将直接保存在DiskInode中的内容移到数据块中
+    ///
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时内容仍然保存在DiskInode中
+    pub fn expand_inline(&mut self, disk_inode: &mut DiskInode, new_size: usize) -> Result<()> {
+        if !disk_inode.is_inline() || new_size <= INLINE_DATA_LIMIT {
+            return Ok(());
+        }
+        let data = disk_inode.take_inline()?;
+        if let Err(err) = self.fill_holes(disk_inode, 0, data.len()) {
+            disk_inode.store_inline(&data)?;
+            return Err(err);
+        }
+        disk_inode.write_at(0, &data, &self.block_device)?;
+        Ok(())
+    }
+
+    /// 文件不超过INLINE_DATA_LIMIT字节时将内容移回DiskInode中, 并回收它占用的所有块
+    ///
+    /// 只有开启了FEATURE_INLINE_DATA的文件系统中的文件和符号链接才会移回
+    pub fn shrink_to_inline(&mut self, disk_inode: &mut DiskInode) -> Result<()> {
+        if self.features & FEATURE_INLINE_DATA == 0
+            || disk_inode.is_dir()
+            || disk_inode.is_compressed()
+            || disk_inode.is_inline()
+            || disk_inode.size as usize > INLINE_DATA_LIMIT
+        {
+            return Ok(());
+        }
+        let mut data = vec![0u8; disk_inode.size as usize];
+        disk_inode.read_at(0, &mut data, &self.block_device)?;
+        for block_id in disk_inode.clear_size(&self.block_device)? {
+            self.dealloc_data(block_id)?;
+        }
+        disk_inode.store_inline(&data)
+    }
+
+    /// 回收DiskInode中[start_block, end_block)范围内的数据块, 让这个范围成为空洞
+    pub fn punch_hole(
+        &mut self,
+        disk_inode: &mut DiskInode,
+        start_block: u32,
+        end_block: u32,
+    ) -> Result<()> {
+        // 拆分extent或者清空索引项时会原地修改索引块
+        self.unshare_index_blocks(disk_inode)?;
+        let block_device = Arc::clone(&self.block_device);
+        let freed = disk_inode.punch_hole(
+            start_block,
+            end_block,
+            &mut || self.alloc_data(),
+            &block_device,
+        )?;
+        for block_id in freed {
+            self.dealloc_data(block_id)?;
+        }
+        Ok(())
+    }
+
+    /// 读出压缩文件的第chunk个块组, 返回COMPRESSED_CHUNK_SZ字节
+    ///
+    /// 块组的内容无法解压时返回EfsError::Corrupted
+    fn load_chunk(&self, disk_inode: &DiskInode, chunk: u32) -> Result<Vec<u8>> {
+        let start_block = chunk * CHUNK_BLOCKS;
+        let blocks = disk_inode.get_block_ids(
+            start_block,
+            start_block + CHUNK_BLOCKS,
+            &self.block_device,
+        )?;
+        let stored = blocks.iter().take_while(|&&block_id| block_id != 0).count();
+        if blocks[stored..].iter().any(|&block_id| block_id != 0) {
+            return Err(EfsError::Corrupted);
+        }
+        let mut raw = vec![0u8; stored * BLOCK_SZ];
+        for (data, &block_id) in raw.chunks_mut(BLOCK_SZ).zip(blocks.iter()) {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .read(0, |block: &DataBlock| data.copy_from_slice(block))?;
+        }
+        if stored == 0 || stored == CHUNK_BLOCKS as usize {
+            raw.resize(COMPRESSED_CHUNK_SZ, 0);
+            return Ok(raw);
+        }
+        let len = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+        let compressed = raw.get(4..4 + len).ok_or(EfsError::Corrupted)?;
+        let mut data = vec![0u8; COMPRESSED_CHUNK_SZ];
+        if lz::decompress(compressed, &mut data)? != COMPRESSED_CHUNK_SZ {
+            return Err(EfsError::Corrupted);
+        }
+        Ok(data)
+    }
+
+    /// 将data写为压缩文件的第chunk个块组, data的长度必须为COMPRESSED_CHUNK_SZ
+    ///
+    /// 内容总是写入新分配的块, 之后再回收原来的块, 因此不会修改被快照共享的块;
+    /// 全为0的块组成为空洞, 压缩之后不能节省块时按原样保存;
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时块组保持不变
+    fn store_chunk(&mut self, disk_inode: &mut DiskInode, chunk: u32, data: &[u8]) -> Result<()> {
+        let stored: Vec<u8> = if data.iter().all(|&byte| byte == 0) {
+            Vec::new()
+        } else {
+            let compressed = lz::compress(data);
+            if (4 + compressed.len()).div_ceil(BLOCK_SZ) < CHUNK_BLOCKS as usize {
+                let mut stored = (compressed.len() as u32).to_le_bytes().to_vec();
+                stored.extend_from_slice(&compressed);
+                stored
+            } else {
+                data.to_vec()
+            }
+        };
+        let start_block = chunk * CHUNK_BLOCKS;
+        let old_blocks = disk_inode.get_block_ids(
+            start_block,
+            start_block + CHUNK_BLOCKS,
+            &self.block_device,
+        )?;
+        let mut new_blocks = self.alloc_data_blocks(stored.len().div_ceil(BLOCK_SZ) as u32)?;
+        for (data, &block_id) in stored.chunks(BLOCK_SZ).zip(new_blocks.iter()) {
+            get_zeroed_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |block: &mut DataBlock| {
+                    block[..data.len()].copy_from_slice(data)
+                })?;
+        }
+        // 新旧两边都是空洞的部分不需要修改
+        let changed = (0..CHUNK_BLOCKS as usize)
+            .rposition(|i| old_blocks[i] != 0 || i < new_blocks.len())
+            .map_or(0, |i| i + 1);
+        new_blocks.resize(changed, 0);
+        let block_device = Arc::clone(&self.block_device);
+        let freed = self.unshare_index_blocks(disk_inode).and_then(|_| {
+            disk_inode.set_block_ids(
+                start_block,
+                &new_blocks,
+                &mut || self.alloc_data(),
+                &block_device,
+            )
+        });
+        let freed = match freed {
+            Ok(freed) => freed,
+            Err(err) => {
+                for block_id in new_blocks.into_iter().filter(|&block_id| block_id != 0) {
+                    self.dealloc_data(block_id)?;
+                }
+                return Err(err);
+            }
+        };
+        let old_blocks = old_blocks.into_iter().filter(|&block_id| block_id != 0);
+        for block_id in freed.into_iter().chain(old_blocks) {
+            self.dealloc_data(block_id)?;
+        }
+        Ok(())
+    }
+
+    /// 从压缩文件的offset字节处开始读取数据到buf中, 返回实际读取的字节数
+    pub fn read_compressed(
+        &self,
+        disk_inode: &DiskInode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let end = (offset + buf.len()).min(disk_inode.size as usize);
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / COMPRESSED_CHUNK_SZ;
+            let chunk_start = chunk * COMPRESSED_CHUNK_SZ;
+            let chunk_end = (chunk_start + COMPRESSED_CHUNK_SZ).min(end);
+            let data = self.load_chunk(disk_inode, chunk as u32)?;
+            buf[pos - offset..chunk_end - offset]
+                .copy_from_slice(&data[pos - chunk_start..chunk_end - chunk_start]);
+            pos = chunk_end;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    /// 向压缩文件的offset字节处写入buf, 范围超出文件末尾时会扩大文件
+    ///
+    /// 涉及的每个块组都会被读出、修改之后重新压缩;
+    /// 范围超过文件最大大小时返回EfsError::FileTooLarge;
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时之前的块组可能已经写入
+    pub fn write_compressed(
+        &mut self,
+        disk_inode: &mut DiskInode,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize> {
+        let end = offset + buf.len();
+        if end > disk_inode.max_size() {
+            return Err(EfsError::FileTooLarge);
+        }
+        let old_size = disk_inode.size as usize;
+        // 先扩大文件, 让新的块组落在文件的块编号范围之内
+        disk_inode.size = old_size.max(end) as u32;
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / COMPRESSED_CHUNK_SZ;
+            let chunk_start = chunk * COMPRESSED_CHUNK_SZ;
+            let chunk_end = (chunk_start + COMPRESSED_CHUNK_SZ).min(end);
+            let mut data = self.load_chunk(disk_inode, chunk as u32)?;
+            data[pos - chunk_start..chunk_end - chunk_start]
+                .copy_from_slice(&buf[pos - offset..chunk_end - offset]);
+            if let Err(err) = self.store_chunk(disk_inode, chunk as u32, &data) {
+                // 只保留已经写入的部分
+                disk_inode.size = old_size.max(pos) as u32;
+                return Err(err);
+            }
+            pos = chunk_end;
+        }
+        Ok(buf.len())
+    }
+
+    /// 回收一个inode, 回收前会将DiskInode以及它的元数据清零
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> Result<()> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let inode_size = self.inode_size;
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| {
+                data_block[block_offset..block_offset + inode_size]
+                    .iter_mut()
+                    .for_each(|byte| *byte = 0);
+            })?;
+        self.inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize)?;
+        self.modify_super_block(|super_block| super_block.free_inodes += 1)
+    }
+
+    /// 为一个新建的空目录写入"."和".."两个目录项
+    pub fn init_dir(
+        &mut self,
+        disk_inode: &mut DiskInode,
+        inode_id: u32,
+        parent_inode_id: u32,
+    ) -> Result<()> {
+        if !disk_inode.is_dir() || disk_inode.size != 0 {
+            return Err(EfsError::InvalidArgument);
+        }
+        self.fill_holes(disk_inode, 0, 2 * DIRENT_SZ)?;
+        disk_inode.write_at(
+            0,
+            DirEntry::new(".", inode_id)?.as_bytes(),
+            &self.block_device,
+        )?;
+        disk_inode.write_at(
+            DIRENT_SZ,
+            DirEntry::new("..", parent_inode_id)?.as_bytes(),
+            &self.block_device,
+        )?;
+        Ok(())
+    }
+
+    /// 是否开启了目录索引
+    pub fn has_dir_index(&self) -> bool {
+        self.features & FEATURE_DIR_INDEX != 0
+    }
+
+    /// 目录中所有非空的目录项
+    fn dir_entries(&self, disk_inode: &DiskInode) -> Result<Vec<DirEntry>> {
+        if !disk_inode.is_dir() {
+            return Err(EfsError::NotDir);
+        }
+        let mut entries = Vec::new();
+        for i in 0..disk_inode.size as usize / DIRENT_SZ {
+            let mut dirent = DirEntry::empty();
+            disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device)?;
+            if !dirent.name().is_empty() {
+                entries.push(dirent);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// 将inode_id对应的目录按照哈希目录的格式重写, 重写后目录至少有min_buckets个块
+    ///
+    /// 新的内容先分批写入空闲的块, 这些块直到最后一次事务才被标记为已分配,
+    /// 同一次事务中让目录指向新的块并回收原本的块, 因此中途崩溃时目录保持不变;
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时目录同样保持不变
+    pub fn rebuild_dir_index(&mut self, inode_id: u32, min_buckets: u32) -> Result<()> {
+        let block_device = Arc::clone(&self.block_device);
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let inode_block = get_block_cache(block_id as usize, Arc::clone(&block_device))?;
+        let mut disk_inode = inode_block
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.clone())?;
+        let entries = self.dir_entries(&disk_inode)?;
+        let (buckets, data) = dir_index::build(&entries, min_buckets);
+        if (self.free_data_blocks()? as usize) < dir_index::blocks_needed(buckets) {
+            return Err(EfsError::NoSpace);
+        }
+        let bits = self
+            .data_bitmap
+            .find_free(&block_device, buckets as usize)?
+            .ok_or(EfsError::NoSpace)?;
+        let blocks: Vec<u32> = bits
+            .iter()
+            .map(|&bit| bit as u32 + self.data_area_start_block)
+            .collect();
+        for (i, (&block_id, content)) in blocks.iter().zip(data.chunks(BLOCK_SZ)).enumerate() {
+            get_zeroed_block_cache(block_id as usize, Arc::clone(&block_device))
+                .lock()
+                .modify(0, |block: &mut DataBlock| block.copy_from_slice(content))?;
+            if (i + 1) % REBUILD_BATCH_BLOCKS == 0 {
+                self.commit()?;
+            }
+        }
+        // 分配新的块, 让目录指向它们并回收原本的块
+        for &bit in bits.iter() {
+            self.data_bitmap.set_allocated(&block_device, bit, true)?;
+        }
+        self.modify_super_block(|super_block| {
+            super_block.free_data_blocks = super_block.free_data_blocks.saturating_sub(buckets)
+        })?;
+        // 新的目录和原本的目录共用除了块以外的所有字段
+        let mut indexed = disk_inode.clone();
+        indexed.clear_size(&block_device)?;
+        indexed.set_hashed();
+        indexed.size = data.len() as u32;
+        for block_id in
+            indexed.fill_holes(0, buckets, blocks, &mut || self.alloc_data(), &block_device)?
+        {
+            self.dealloc_data(block_id)?;
+        }
+        for block_id in disk_inode.clear_size(&block_device)? {
+            self.dealloc_data(block_id)?;
+        }
+        inode_block
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                *disk_inode = indexed
+            })?;
+        self.commit()
+    }
+
+    /// 回收一个数据块, 块的内容保持不变, 直到下一次被分配时才会清零
+    ///
+    /// 这样回收大量的块时只需要修改位图, 不会让块本身成为脏块;
+    /// 块还被快照引用时只减少它的引用计数;
+    /// 块不在数据区域内说明索引已经损坏, 返回EfsError::Corrupted
+    pub fn dealloc_data(&mut self, block_id: u32) -> Result<()> {
+        let bit = self.data_bit(block_id)?;
+        let count = self.refcount(block_id)?;
+        if count > 0 {
+            return self.set_refcount(block_id, count - 1);
+        }
+        self.data_bitmap.dealloc(&self.block_device, bit)?;
+        self.modify_super_block(|super_block| super_block.free_data_blocks += 1)
+    }
+
+    /// 数据块在数据位图中对应的位, 块不在数据区域内时返回EfsError::Corrupted
+    fn data_bit(&self, block_id: u32) -> Result<usize> {
+        let bit = block_id
+            .checked_sub(self.data_area_start_block)
+            .ok_or(EfsError::Corrupted)? as usize;
+        if bit >= self.data_bitmap.size() {
+            return Err(EfsError::Corrupted);
+        }
+        Ok(bit)
+    }
+
+    /// 将src块的内容复制到dst块中
+    fn copy_block(&self, src: u32, dst: u32) -> Result<()> {
+        let mut data = [0u8; BLOCK_SZ];
+        get_block_cache(src as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |block: &DataBlock| data.copy_from_slice(block))?;
+        get_zeroed_block_cache(dst as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |block: &mut DataBlock| block.copy_from_slice(&data))
+    }
+
+    /// 是否存在快照
+    pub fn has_snapshots(&self) -> bool {
+        self.snapshot_table != 0
+    }
+
+    /// 对快照表进行只读访问
+    fn read_snapshot_table<V>(&self, f: impl FnOnce(&SnapshotTable) -> V) -> Result<V> {
+        get_block_cache(self.snapshot_table as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, f)
+    }
+
+    /// 对快照表进行修改
+    fn modify_snapshot_table<V>(&self, f: impl FnOnce(&mut SnapshotTable) -> V) -> Result<V> {
+        get_block_cache(self.snapshot_table as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(0, f)
+    }
+
+    /// 数据块除了第一个所有者之外还被多少个文件系统树引用, 没有快照时总是为0
+    pub fn refcount(&self, block_id: u32) -> Result<u8> {
+        if !self.has_snapshots() {
+            return Ok(0);
+        }
+        let bit = self.data_bit(block_id)?;
+        let mut count = [0u8];
+        let block_device = Arc::clone(&self.block_device);
+        self.read_snapshot_table(|table| table.refcounts.read_at(bit, &mut count, &block_device))??;
+        Ok(count[0])
+    }
+
+    /// 设置数据块的引用计数, 引用计数文件不够大时会先扩大
+    ///
+    /// 没有快照时只能设置为0, 否则返回EfsError::InvalidArgument
+    pub fn set_refcount(&mut self, block_id: u32, count: u8) -> Result<()> {
+        let bit = self.data_bit(block_id)?;
+        if self.refcount(block_id)? == count {
+            return Ok(());
+        }
+        if !self.has_snapshots() {
+            return Err(EfsError::InvalidArgument);
+        }
+        // 扩大引用计数文件时可能会回收块, 不能在持有快照表的锁时进行
+        let mut refcounts = self.read_snapshot_table(|table| table.refcounts.clone())?;
+        self.alloc_holes(&mut refcounts, bit, 1)?;
+        refcounts.write_at(bit, &[count], &self.block_device)?;
+        self.modify_snapshot_table(|table| table.refcounts = refcounts)
+    }
+
+    /// 获取一个可以在原地修改的块
+    ///
+    /// 块被快照共享时将内容复制到新分配的块中, 并减少原来的块的引用计数, 返回新块的编号;
+    /// 否则直接返回原来的块编号
+    pub fn unshare_block(&mut self, block_id: u32) -> Result<u32> {
+        let count = self.refcount(block_id)?;
+        if count == 0 {
+            return Ok(block_id);
+        }
+        let new_block_id = self.alloc_data()?;
+        self.copy_block(block_id, new_block_id)?;
+        self.set_refcount(block_id, count - 1)?;
+        Ok(new_block_id)
+    }
+
+    /// 让DiskInode的索引块或extent块只属于这个inode
+    fn unshare_index_blocks(&mut self, disk_inode: &mut DiskInode) -> Result<()> {
+        if !self.has_snapshots() {
+            return Ok(());
+        }
+        let block_device = Arc::clone(&self.block_device);
+        disk_inode.unshare_index_blocks(&mut |block_id| self.unshare_block(block_id), &block_device)
+    }
+
+    /// 让DiskInode中[offset, offset + len)范围内已经分配的数据块以及所有的索引块只属于这个inode
+    ///
+    /// 被快照共享的块会被复制到新分配的块中, 之后可以直接写入; 没有快照时什么也不做
+    pub fn unshare(&mut self, disk_inode: &mut DiskInode, offset: usize, len: usize) -> Result<()> {
+        if !self.has_snapshots() || disk_inode.is_inline() || len == 0 {
+            return Ok(());
+        }
+        self.unshare_index_blocks(disk_inode)?;
+        let start_block = (offset / BLOCK_SZ) as u32;
+        let end_block = ((offset + len).div_ceil(BLOCK_SZ) as u32).min(disk_inode.data_blocks());
+        if start_block >= end_block {
+            return Ok(());
+        }
+        let block_device = Arc::clone(&self.block_device);
+        let blocks = disk_inode.get_block_ids(start_block, end_block, &block_device)?;
+        let mut unshared: Vec<u32> = Vec::with_capacity(blocks.len());
+        for &block_id in blocks.iter() {
+            unshared.push(if block_id == 0 {
+                0
+            } else {
+                self.unshare_block(block_id)?
+            });
+        }
+        if unshared != blocks {
+            let freed = disk_inode.set_block_ids(
+                start_block,
+                &unshared,
+                &mut || self.alloc_data(),
+                &block_device,
+            )?;
+            for block_id in freed {
+                self.dealloc_data(block_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// inode区域的块数, inode区域之后紧跟着数据位图
+    fn inode_area_blocks(&self) -> usize {
+        self.data_bitmap.block_range().start - self.inode_area_start_block as usize
+    }
+
+    /// 当前文件系统或者快照镜像中的第idx个元数据块, inode位图之后是inode区域
+    ///
+    /// 快照镜像中没有保存的块返回0
+    fn metadata_block(&self, image: Option<&DiskInode>, idx: usize) -> Result<u32> {
+        let inode_bitmap_blocks = self.inode_bitmap.block_range();
+        match image {
+            Some(image) => image.get_block_id(1 + idx as u32, &self.block_device),
+            None if idx < inode_bitmap_blocks.len() => Ok((inode_bitmap_blocks.start + idx) as u32),
+            None => Ok(self.inode_area_start_block + (idx - inode_bitmap_blocks.len()) as u32),
+        }
+    }
+
+    /// 读出当前文件系统或者快照镜像中的inode位图
+    fn load_inode_bitmap(&self, image: Option<&DiskInode>) -> Result<Vec<u64>> {
+        let mut bits: Vec<u64> = Vec::new();
+        for idx in 0..self.inode_bitmap.block_range().len() {
+            let block_id = self.metadata_block(image, idx)?;
+            if block_id == 0 {
+                bits.extend([0u64; BLOCK_BITS / 64]);
+                continue;
+            }
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .read(0, |block: &[u64; BLOCK_BITS / 64]| {
+                    bits.extend_from_slice(block)
+                })?;
+        }
+        Ok(bits)
+    }
+
+    /// inode区域的第k个块中是否有已经分配的inode
+    fn inode_block_in_use(&self, bits: &[u64], k: usize) -> bool {
+        let inodes_per_block = BLOCK_SZ / self.inode_size;
+        (k * inodes_per_block..(k + 1) * inodes_per_block)
+            .any(|inode_id| bits[inode_id / 64] & (1u64 << (inode_id % 64)) != 0)
+    }
+
+    /// 位于block_id块内offset处的inode占用的所有块, 包括扩展属性块
+    fn inode_blocks_at(&self, block_id: u32, offset: usize) -> Result<Vec<u32>> {
+        let cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device))?;
+        let disk_inode = cache
+            .lock()
+            .read(offset, |disk_inode: &DiskInode| disk_inode.clone())?;
+        let mut blocks = disk_inode.blocks(&self.block_device)?;
+        if self.has_inode_meta() {
+            let xattr_block = cache.lock().read(
+                offset + core::mem::size_of::<DiskInode>(),
+                |meta: &DiskInodeMeta| meta.xattr_block,
+            )?;
+            if xattr_block != 0 {
+                blocks.push(xattr_block);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// 一棵文件系统树中所有inode占用的块, 包括数据块、索引块以及扩展属性块
+    ///
+    /// image为None时为当前的文件系统树, 否则为快照镜像中保存的文件系统树;
+    /// 同一个块被多个inode引用时会出现多次
+    pub fn tree_blocks(&self, image: Option<&DiskInode>) -> Result<Vec<u32>> {
+        let bits = self.load_inode_bitmap(image)?;
+        let inode_bitmap_blocks = self.inode_bitmap.block_range().len();
+        let inodes_per_block = BLOCK_SZ / self.inode_size;
+        let mut blocks: Vec<u32> = Vec::new();
+        for k in 0..self.inode_area_blocks() {
+            let block_id = self.metadata_block(image, inode_bitmap_blocks + k)?;
+            if block_id == 0 {
+                continue;
+            }
+            for j in 0..inodes_per_block {
+                let inode_id = k * inodes_per_block + j;
+                if bits[inode_id / 64] & (1u64 << (inode_id % 64)) != 0 {
+                    blocks.extend(self.inode_blocks_at(block_id, j * self.inode_size)?);
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// 快照表、引用计数文件以及所有快照镜像占用的块
+    pub fn snapshot_metadata_blocks(&self) -> Result<Vec<u32>> {
+        if !self.has_snapshots() {
+            return Ok(Vec::new());
+        }
+        let refcounts = self.read_snapshot_table(|table| table.refcounts.clone())?;
+        let mut blocks = refcounts.blocks(&self.block_device)?;
+        blocks.push(self.snapshot_table);
+        for (_, image) in self.snapshot_images()? {
+            blocks.extend(image.blocks(&self.block_device)?);
+        }
+        Ok(blocks)
+    }
+
+    /// 所有快照在快照表中的位置以及它们的镜像
+    pub fn snapshot_images(&self) -> Result<Vec<(usize, DiskInode)>> {
+        if !self.has_snapshots() {
+            return Ok(Vec::new());
+        }
+        self.read_snapshot_table(|table| {
+            table
+                .images
+                .iter()
+                .enumerate()
+                .filter(|(_, image)| image.size > 0)
+                .map(|(slot, image)| (slot, image.clone()))
+                .collect()
+        })
+    }
+
+    /// 读取快照镜像中的快照头
+    fn snapshot_info(&self, image: &DiskInode) -> Result<SnapshotInfo> {
+        let block_id = image.get_block_id(0, &self.block_device)?;
+        if block_id == 0 {
+            return Err(EfsError::Corrupted);
+        }
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, |header: &SnapshotHeader| SnapshotInfo {
+                name: header.name().into(),
+                created: header.created,
+            })
+    }
+
+    /// 列出所有的快照
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        self.snapshot_images()?
+            .iter()
+            .map(|(_, image)| self.snapshot_info(image))
+            .collect()
+    }
+
+    /// 查找名为name的快照, 返回它在快照表中的位置以及它的镜像, 不存在时返回EfsError::NotFound
+    fn find_snapshot(&self, name: &str) -> Result<(usize, DiskInode)> {
+        for (slot, image) in self.snapshot_images()? {
+            if self.snapshot_info(&image)?.name == name {
+                return Ok((slot, image));
+            }
+        }
+        Err(EfsError::NotFound)
+    }
+
+    /// 为当前文件系统树创建一个快照最多需要的数据块数量,
+    /// 包括快照表、引用计数文件、快照镜像以及它们的索引块
+    ///
+    /// blocks为当前文件系统树中的块, 引用计数文件中只有记录它们的块需要分配;
+    /// 快照镜像只保存有已分配inode的inode块, 和build_snapshot_image使用相同的判断
+    fn snapshot_blocks_needed(&self, blocks: &[u32]) -> Result<u32> {
+        let index_blocks = |blocks: usize| blocks.div_ceil(BLOCK_SZ / 4) + 1;
+        let mut refcount_blocks: Vec<u32> = blocks
+            .iter()
+            .map(|&block_id| block_id.saturating_sub(self.data_area_start_block) / BLOCK_SZ as u32)
+            .collect();
+        refcount_blocks.sort_unstable();
+        refcount_blocks.dedup();
+        let bits = self.load_inode_bitmap(None)?;
+        let inode_bitmap_blocks = self.inode_bitmap.block_range().len();
+        let inode_blocks = (0..self.inode_area_blocks())
+            .filter(|&k| self.inode_block_in_use(&bits, k))
+            .count();
+        // 索引块的数量取决于块在文件中的位置, 按照文件可能的最大长度计算
+        let max_refcount_blocks = self.data_bitmap.size().div_ceil(BLOCK_SZ);
+        let max_image_blocks = 1 + inode_bitmap_blocks + self.inode_area_blocks();
+        Ok((1
+            + refcount_blocks.len()
+            + index_blocks(max_refcount_blocks)
+            + 1
+            + inode_bitmap_blocks
+            + inode_blocks
+            + index_blocks(max_image_blocks)) as u32)
+    }
+
+    /// 创建快照表, 清零的块中所有的文件都为空
+    fn create_snapshot_table(&mut self) -> Result<()> {
+        let snapshot_table = self.alloc_data()?;
+        get_zeroed_block_cache(snapshot_table as usize, Arc::clone(&self.block_device));
+        self.snapshot_table = snapshot_table;
+        self.features |= FEATURE_SNAPSHOTS;
+        let features = self.features;
+        self.modify_super_block(|super_block| {
+            super_block.snapshot_table = snapshot_table;
+            super_block.features = features;
+        })
+    }
+
+    /// 回收快照表以及引用计数文件, 之后所有的块都只有一个所有者
+    fn remove_snapshot_table(&mut self) -> Result<()> {
+        let refcounts = self.read_snapshot_table(|table| table.refcounts.clone())?;
+        let snapshot_table = self.snapshot_table;
+        self.snapshot_table = 0;
+        self.features &= !FEATURE_SNAPSHOTS;
+        let features = self.features;
+        self.modify_super_block(|super_block| {
+            super_block.snapshot_table = 0;
+            super_block.features = features;
+        })?;
+        for block_id in refcounts.blocks(&self.block_device)? {
+            self.dealloc_data(block_id)?;
+        }
+        self.dealloc_data(snapshot_table)
+    }
+
+    /// 让blocks中的每个块都多一个所有者
+    fn add_owners(&mut self, blocks: &mut [u32]) -> Result<()> {
+        // 按块编号排序, 让引用计数文件按顺序被修改
+        blocks.sort_unstable();
+        for (i, &block_id) in blocks.iter().enumerate() {
+            let count = self
+                .refcount(block_id)?
+                .checked_add(1)
+                .ok_or(EfsError::Corrupted)?;
+            self.set_refcount(block_id, count)?;
+            if (i + 1) % SNAPSHOT_BATCH_BLOCKS == 0 {
+                self.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 让blocks中的每个块都少一个所有者, 没有其他所有者的块会被回收
+    fn release_owners(&mut self, blocks: &mut [u32]) -> Result<()> {
+        blocks.sort_unstable();
+        for (i, &block_id) in blocks.iter().enumerate() {
+            self.dealloc_data(block_id)?;
+            if (i + 1) % SNAPSHOT_BATCH_BLOCKS == 0 {
+                self.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 在快照表的第slot个位置写入快照镜像: 快照头 | inode位图的副本 | inode区域的副本
+    ///
+    /// inode区域中没有已分配inode的块保持为空洞
+    fn build_snapshot_image(&mut self, slot: usize, name: &str) -> Result<DiskInode> {
+        let mut image = self.read_snapshot_table(|table| table.images[slot].clone())?;
+        image.initialize(DiskInodeType::File, 0);
+        self.alloc_holes(&mut image, 0, BLOCK_SZ)?;
+        let header_block = image.get_block_id(0, &self.block_device)?;
+        get_zeroed_block_cache(header_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |header: &mut SnapshotHeader| {
+                header.initialize(name, now())
+            })?;
+        let bits = self.load_inode_bitmap(None)?;
+        let inode_bitmap_blocks = self.inode_bitmap.block_range().len();
+        let metadata_blocks = inode_bitmap_blocks + self.inode_area_blocks();
+        let mut copied = 0;
+        for idx in 0..metadata_blocks {
+            if idx >= inode_bitmap_blocks
+                && !self.inode_block_in_use(&bits, idx - inode_bitmap_blocks)
+            {
+                continue;
+            }
+            self.alloc_holes(&mut image, (1 + idx) * BLOCK_SZ, BLOCK_SZ)?;
+            let dst = image.get_block_id(1 + idx as u32, &self.block_device)?;
+            self.copy_block(self.metadata_block(None, idx)?, dst)?;
+            copied += 1;
+            if copied % SNAPSHOT_BATCH_BLOCKS == 0 {
+                self.commit()?;
+            }
+        }
+        // 末尾没有保存的块同样计入镜像的大小
+        self.alloc_holes(&mut image, (1 + metadata_blocks) * BLOCK_SZ, 0)?;
+        Ok(image)
+    }
+
+    /// 创建一个名为name的快照, 保存当前整个文件系统树
+    ///
+    /// 快照只复制inode位图和inode区域, 数据块、索引块以及扩展属性块通过引用计数与当前的文件系统树共享,
+    /// 之后修改共享的块时会先复制一份;
+    /// 名字为空或者包含'\0'时返回EfsError::InvalidName, 超过SNAPSHOT_NAME_LIMIT时返回EfsError::NameTooLong,
+    /// 同名的快照已经存在时返回EfsError::Exists,
+    /// 快照数量已经达到MAX_SNAPSHOTS或者空闲的数据块不足时返回EfsError::NoSpace;
+    /// 中途崩溃只会让引用计数偏大或者泄漏块, 可以通过fsck修复
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.abort_on_error(|fs| {
+            if name.is_empty() || name.contains('\0') {
+                return Err(EfsError::InvalidName);
+            }
+            if name.len() > SNAPSHOT_NAME_LIMIT {
+                return Err(EfsError::NameTooLong);
+            }
+            if fs.find_snapshot(name).is_ok() {
+                return Err(EfsError::Exists);
+            }
+            let mut blocks = fs.tree_blocks(None)?;
+            if fs.snapshot_images()?.len() >= MAX_SNAPSHOTS
+                || fs.free_data_blocks()? < fs.snapshot_blocks_needed(&blocks)?
+            {
+                return Err(EfsError::NoSpace);
+            }
+            fs.set_recount_needed(true)?;
+            if !fs.has_snapshots() {
+                fs.create_snapshot_table()?;
+            }
+            let slot = fs
+                .read_snapshot_table(|table| table.images.iter().position(|image| image.size == 0))?
+                .ok_or(EfsError::Corrupted)?;
+            // 先增加引用计数, 之后当前文件系统树中的块都不会在原地被修改
+            fs.add_owners(&mut blocks)?;
+            let image = fs.build_snapshot_image(slot, name)?;
+            fs.modify_snapshot_table(|table| table.images[slot] = image)?;
+            fs.commit()?;
+            fs.set_recount_needed(false)
+        })
+    }
+
+    /// 删除名为name的快照, 回收只被这个快照引用的块
+    ///
+    /// 快照不存在时返回EfsError::NotFound; 删除最后一个快照时同时回收快照表并清除FEATURE_SNAPSHOTS
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        self.abort_on_error(|fs| {
+            let (slot, mut image) = fs.find_snapshot(name)?;
+            let mut blocks = fs.tree_blocks(Some(&image))?;
+            fs.set_recount_needed(true)?;
+            // 先从快照表中移除, 之后崩溃只会让引用计数偏大或者泄漏块
+            let mut image_blocks = image.clear_size(&fs.block_device)?;
+            fs.modify_snapshot_table(|table| table.images[slot] = image)?;
+            fs.commit()?;
+            fs.release_owners(&mut blocks)?;
+            fs.release_owners(&mut image_blocks)?;
+            if fs.snapshot_images()?.is_empty() {
+                fs.remove_snapshot_table()?;
+            }
+            fs.commit()?;
+            fs.set_recount_needed(false)
+        })
+    }
+
+    /// 将整个文件系统恢复到名为name的快照创建时的状态, 快照本身仍然保留
+    ///
+    /// 调用之前需要释放所有打开的Inode, 它们对应的文件在回滚之后可能已经不存在;
+    /// 快照不存在时返回EfsError::NotFound;
+    /// 覆盖inode区域的过程中崩溃会让文件系统处于新旧混合的状态, 需要通过fsck修复
+    pub fn rollback_snapshot(&mut self, name: &str) -> Result<()> {
+        self.abort_on_error(|fs| {
+            let (_, image) = fs.find_snapshot(name)?;
+            let mut snapshot_blocks = fs.tree_blocks(Some(&image))?;
+            let mut live_blocks = fs.tree_blocks(None)?;
+            fs.set_recount_needed(true)?;
+            // 快照中的块将同时被恢复出的文件系统树引用
+            fs.add_owners(&mut snapshot_blocks)?;
+            fs.commit()?;
+            let metadata_blocks = fs.inode_bitmap.block_range().len() + fs.inode_area_blocks();
+            for idx in 0..metadata_blocks {
+                let src = fs.metadata_block(Some(&image), idx)?;
+                let dst = fs.metadata_block(None, idx)?;
+                if src == 0 {
+                    get_zeroed_block_cache(dst as usize, Arc::clone(&fs.block_device));
+                } else {
+                    fs.copy_block(src, dst)?;
+                }
+                if (idx + 1) % SNAPSHOT_BATCH_BLOCKS == 0 {
+                    fs.commit()?;
+                }
+            }
+            // 原来的文件系统树不再引用它的块
+            fs.release_owners(&mut live_blocks)?;
+            fs.recount_free()?;
+            fs.commit()?;
+            fs.set_recount_needed(false)
+        })
+    }
+
+    /// 如果block_id位于range中, 将它复制到一个新分配的数据块中并返回新的块编号
+    fn relocate_block(&mut self, block_id: u32, range: &Range<u32>) -> Result<u32> {
+        if !range.contains(&block_id) {
+            return Ok(block_id);
+        }
+        let new_block_id = self.alloc_data()?;
+        self.copy_block(block_id, new_block_id)?;
+        Ok(new_block_id)
+    }
+
+    /// 将一个inode占用的位于range中的块搬移到range之外, 原来的块保持分配状态
+    ///
+    /// 每搬移一批数据块就写回DiskInode并提交一次, 以免一次事务超过日志的容量
+    fn relocate_inode(&mut self, inode_id: u32, range: &Range<u32>) -> Result<()> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let block_device = Arc::clone(&self.block_device);
+        let inode_block = get_block_cache(block_id as usize, Arc::clone(&block_device))?;
+        let mut disk_inode = inode_block
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.clone())?;
+        if !disk_inode.is_inline() {
+            disk_inode.unshare_index_blocks(
+                &mut |block_id| self.relocate_block(block_id, range),
+                &block_device,
+            )?;
+            let data_blocks = disk_inode.data_blocks();
+            for start_block in (0..data_blocks).step_by(RELOCATE_BATCH_BLOCKS as usize) {
+                let end_block = (start_block + RELOCATE_BATCH_BLOCKS).min(data_blocks);
+                let blocks = disk_inode.get_block_ids(start_block, end_block, &block_device)?;
+                if !blocks.iter().any(|block_id| range.contains(block_id)) {
+                    continue;
+                }
+                let mut relocated: Vec<u32> = Vec::with_capacity(blocks.len());
+                for &block_id in blocks.iter() {
+                    relocated.push(self.relocate_block(block_id, range)?);
+                }
+                let freed = disk_inode.set_block_ids(
+                    start_block,
+                    &relocated,
+                    &mut || self.alloc_data(),
+                    &block_device,
+                )?;
+                for block_id in freed
+                    .into_iter()
+                    .filter(|block_id| !range.contains(block_id))
+                {
+                    self.dealloc_data(block_id)?;
+                }
+                inode_block
+                    .lock()
+                    .modify(block_offset, |old: &mut DiskInode| {
+                        *old = disk_inode.clone()
+                    })?;
+                self.commit()?;
+            }
+            inode_block
+                .lock()
+                .modify(block_offset, |old: &mut DiskInode| *old = disk_inode)?;
+        }
+        if let Some(xattr_block) = self.read_inode_meta(inode_id, |meta| meta.xattr_block)? {
+            if xattr_block != 0 && range.contains(&xattr_block) {
+                let new_xattr_block = self.relocate_block(xattr_block, range)?;
+                self.modify_inode_meta(inode_id, |meta| meta.xattr_block = new_xattr_block)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 让数据区域中range范围内的块不再被任何inode引用
+    ///
+    /// 先将范围内空闲的块标记为已分配, 之后的分配就不会落在范围内;
+    /// 再逐个搬移每个inode位于范围内的块, 每个inode的块分批作为多次事务提交,
+    /// 完成后范围内的块全部处于已分配但没有被引用的状态
+    fn evacuate(&mut self, range: Range<u32>) -> Result<()> {
+        let mut reserved = 0;
+        for block_id in range.clone() {
+            let bit = self.data_bit(block_id)?;
+            if !self.data_bitmap.is_allocated(&self.block_device, bit)? {
+                self.data_bitmap
+                    .set_allocated(&self.block_device, bit, true)?;
+                reserved += 1;
+            }
+        }
+        self.modify_super_block(|super_block| {
+            super_block.free_data_blocks = super_block.free_data_blocks.saturating_sub(reserved)
+        })?;
+        self.commit()?;
+        let bits = self.load_inode_bitmap(None)?;
+        for inode_id in 0..self.inode_bitmap.size() {
+            if bits[inode_id / 64] & (1u64 << (inode_id % 64)) != 0 {
+                self.relocate_inode(inode_id as u32, &range)?;
+                self.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 在线扩大文件系统, 让它占用块设备上的前new_total_blocks个块
+    ///
+    /// 调用之前块设备需要已经扩大到至少new_total_blocks个块, 打开的Inode在扩大之后仍然可以使用;
+    /// 新增的块全部加入数据区域, 数据位图的容量不够时会向后扩大,
+    /// 原本位于数据区域开头的块会先被搬移到其他空闲的块中, 之后整个数据位图按照新的起始位置重写;
+    /// new_total_blocks小于当前的总块数时返回EfsError::InvalidArgument,
+    /// 校验和表在创建时预留了CHECKSUM_GROWTH_FACTOR倍于总块数的容量,
+    /// 超出这个容量或者需要扩大数据位图时存在快照, 返回EfsError::Unsupported,
+    /// 没有足够的空闲块来腾出新的数据位图块时返回EfsError::NoSpace
+    pub fn grow(&mut self, new_total_blocks: u32) -> Result<()> {
+        self.abort_on_error(|fs| {
+            let total_blocks = fs.total_blocks()? as u32;
+            if new_total_blocks < total_blocks {
+                return Err(EfsError::InvalidArgument);
+            }
+            if fs.has_checksums()
+                && new_total_blocks as usize > fs.checksum_blocks as usize * CHECKSUMS_PER_BLOCK
+            {
+                return Err(EfsError::Unsupported);
+            }
+            let bitmap_range = fs.data_bitmap.block_range();
+            let bitmap_start = bitmap_range.start as u32;
+            let bitmap_blocks = bitmap_range.len() as u32;
+            let new_bitmap_blocks = (new_total_blocks - bitmap_start)
+                .div_ceil(BLOCK_BITS as u32 + 1)
+                .max(bitmap_blocks);
+            let grown = new_bitmap_blocks - bitmap_blocks;
+            if grown > 0 && fs.has_snapshots() {
+                return Err(EfsError::Unsupported);
+            }
+            // 先在数据位图现有的容量之内扩大数据区域, 不需要移动任何块
+            let data_area_blocks = (new_total_blocks - fs.data_area_start_block)
+                .min((bitmap_blocks as usize * BLOCK_BITS) as u32);
+            let added_blocks = data_area_blocks - fs.data_bitmap.size() as u32;
+            if grown > fs.free_data_blocks()? + added_blocks {
+                return Err(EfsError::NoSpace);
+            }
+            fs.set_recount_needed(true)?;
+            // 空闲的数据块在分配时才会清零, 新增的块只需要让校验和表与它们当前的内容一致
+            if fs.has_checksums() {
+                fs.fix_checksums(total_blocks..new_total_blocks)?;
+            }
+            fs.data_bitmap = Bitmap::new(
+                bitmap_start as usize,
+                bitmap_blocks as usize,
+                data_area_blocks as usize,
+            )?;
+            fs.modify_super_block(|super_block| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_area_blocks = data_area_blocks;
+            })?;
+            fs.recount_free()?;
+            fs.commit()?;
+            block_cache_enable_readahead(&fs.block_device, new_total_blocks as usize);
+            if grown > 0 {
+                // 数据区域开头的grown个块将成为新的数据位图块
+                let data_area_start_block = fs.data_area_start_block;
+                fs.evacuate(data_area_start_block..data_area_start_block + grown)?;
+                let mut bits = vec![0u64; bitmap_blocks as usize * BLOCK_BITS / 64];
+                for block_offset in 0..bitmap_blocks as usize {
+                    get_block_cache(
+                        bitmap_start as usize + block_offset,
+                        Arc::clone(&fs.block_device),
+                    )?
+                    .lock()
+                    .read(0, |block: &[u64; BLOCK_BITS / 64]| {
+                        bits[block_offset * BLOCK_BITS / 64..][..BLOCK_BITS / 64]
+                            .copy_from_slice(block)
+                    })?;
+                }
+                let new_data_area_blocks = new_total_blocks - data_area_start_block - grown;
+                let new_bitmap = Bitmap::new(
+                    bitmap_start as usize,
+                    new_bitmap_blocks as usize,
+                    new_data_area_blocks as usize,
+                )?;
+                for block_id in bitmap_start..bitmap_start + new_bitmap_blocks {
+                    get_zeroed_block_cache(block_id as usize, Arc::clone(&fs.block_device));
+                }
+                // 块编号不变, 在位图中对应的位向前移动grown位
+                for bit in grown as usize..data_area_blocks as usize {
+                    if bits[bit / 64] & (1u64 << (bit % 64)) != 0 {
+                        new_bitmap.set_allocated(&fs.block_device, bit - grown as usize, true)?;
+                    }
+                }
+                fs.data_bitmap = new_bitmap;
+                fs.data_area_start_block += grown;
+                fs.modify_super_block(|super_block| {
+                    super_block.data_bitmap_blocks = new_bitmap_blocks;
+                    super_block.data_area_blocks = new_data_area_blocks;
+                })?;
+                fs.recount_free()?;
+                fs.commit()?;
+            }
+            fs.set_recount_needed(false)
+        })
+    }
+
+    /// 离线缩小文件系统, 让它只占用块设备上的前new_total_blocks个块
+    ///
+    /// 调用之前需要释放所有打开的Inode, 完成之后块设备可以截断到new_total_blocks个块;
+    /// 数据区域末尾被截掉的部分中仍在使用的块会先被搬移到前面空闲的块中, 数据位图的块数保持不变;
+    /// new_total_blocks大于当前的总块数或者放不下数据区域之前的区域时返回EfsError::InvalidArgument,
+    /// 存在快照时返回EfsError::Unsupported, 剩下的数据区域放不下所有已经使用的块时返回EfsError::NoSpace
+    pub fn shrink(&mut self, new_total_blocks: u32) -> Result<()> {
+        self.abort_on_error(|fs| {
+            let total_blocks = fs.total_blocks()? as u32;
+            if new_total_blocks > total_blocks || new_total_blocks <= fs.data_area_start_block {
+                return Err(EfsError::InvalidArgument);
+            }
+            if fs.has_snapshots() {
+                return Err(EfsError::Unsupported);
+            }
+            let data_area_blocks = fs.data_bitmap.size() as u32;
+            let new_data_area_blocks =
+                (new_total_blocks - fs.data_area_start_block).min(data_area_blocks);
+            let used_blocks = data_area_blocks - fs.free_data_blocks()?;
+            if used_blocks > new_data_area_blocks {
+                return Err(EfsError::NoSpace);
+            }
+            fs.set_recount_needed(true)?;
+            let data_area_end = fs.data_area_start_block + data_area_blocks;
+            fs.evacuate(fs.data_area_start_block + new_data_area_blocks..data_area_end)?;
+            // 截掉的块不再属于数据区域, 它们的位需要清零, 以免之后再次扩大时被当作已分配
+            for bit in new_data_area_blocks as usize..data_area_blocks as usize {
+                fs.data_bitmap.set_allocated(&fs.block_device, bit, false)?;
+            }
+            let bitmap_range = fs.data_bitmap.block_range();
+            fs.data_bitmap = Bitmap::new(
+                bitmap_range.start,
+                bitmap_range.len(),
+                new_data_area_blocks as usize,
+            )?;
+            fs.modify_super_block(|super_block| {
+                super_block.total_blocks = new_total_blocks;
+                super_block.data_area_blocks = new_data_area_blocks;
+            })?;
+            fs.recount_free()?;
+            fs.commit()?;
+            block_cache_enable_readahead(&fs.block_device, new_total_blocks as usize);
+            fs.set_recount_needed(false)
+        })
+    }
+}
+
+impl Drop for EasyFileSystem {
+    /// 卸载文件系统时将设备的块以及预读和校验和状态从块缓存中移除, 还没有提交的修改被丢弃
+    ///
+    /// 之后在同一个设备(或者复用了它的地址的新设备)上创建或打开的文件系统不会看到这些状态
+    fn drop(&mut self) {
+        let _ = block_cache_invalidate_device(&self.block_device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use super::EasyFileSystem;
+    use crate::{
+        block_cache::get_block_cache,
+        block_dev::BlockDevice,
+        fsck::fsck,
+        layout::FEATURE_CHECKSUMS,
+        test_util::{create_fs, MemDevice},
+        vfs::Inode,
+        EfsError, BLOCK_BITS, BLOCK_SZ,
+    };
+
+    #[test]
+    fn create_then_open_round_trip() {
+        let device = Arc::new(MemDevice::new(4096));
+        // 放不下各个区域的设备以及没有文件系统的设备
+        assert_eq!(
+            EasyFileSystem::create(device.clone(), 64, 1).err(),
+            Some(EfsError::InvalidArgument)
+        );
+        assert_eq!(
+            EasyFileSystem::open(Arc::new(MemDevice::new(4096))).err(),
+            Some(EfsError::Corrupted)
+        );
+
+        let efs = EasyFileSystem::create(device.clone(), 4096, 1).unwrap();
+        let (free_inodes, free_data_blocks) = {
+            let fs = efs.lock();
+            (fs.free_inodes().unwrap(), fs.free_data_blocks().unwrap())
+        };
+        // 根目录占用了0号inode
+        assert_eq!(free_inodes as usize, BLOCK_BITS - 1);
+        Inode::root_inode(&efs)
+            .create("file")
+            .unwrap()
+            .write_at(0, b"hello")
+            .unwrap();
+        drop(efs);
+
+        let efs = EasyFileSystem::open(device).unwrap();
+        {
+            let fs = efs.lock();
+            assert_eq!(fs.total_blocks(), Ok(4096));
+            assert_eq!(fs.free_inodes(), Ok(free_inodes - 1));
+            assert_eq!(fs.free_data_blocks(), Ok(free_data_blocks - 1));
+        }
+        let mut buf = [0u8; 5];
+        let file = Inode::root_inode(&efs).find("file").unwrap();
+        assert_eq!(file.read_at(0, &mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn abort_drops_uncommitted_changes() {
+        let (_, efs) = create_fs(4096, 0);
+        let block_device = Arc::clone(&efs.lock().block_device);
+        let free = efs.lock().free_data_blocks().unwrap();
+        let block_id = {
+            let mut fs = efs.lock();
+            let block_id = fs.alloc_data().unwrap();
+            get_block_cache(block_id as usize, Arc::clone(&block_device))
+                .unwrap()
+                .lock()
+                .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(9))
+                .unwrap();
+            fs.abort().unwrap();
+            block_id
+        };
+        assert_eq!(efs.lock().free_data_blocks().unwrap(), free);
+        let first = get_block_cache(block_id as usize, Arc::clone(&block_device))
+            .unwrap()
+            .lock()
+            .read(0, |data: &[u8; BLOCK_SZ]| data[0])
+            .unwrap();
+        assert_eq!(first, 0);
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn drop_discards_cached_state_of_device() {
+        let (_, efs) = create_fs(4096, FEATURE_CHECKSUMS);
+        let block_device = Arc::clone(&efs.lock().block_device);
+        let block_id = efs.lock().get_data_block_id(1) as usize;
+        get_block_cache(block_id, Arc::clone(&block_device))
+            .unwrap()
+            .lock()
+            .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(9))
+            .unwrap();
+        drop(efs);
+        // 没有提交的修改随文件系统一起被丢弃, 再次打开时读到的是磁盘上的内容
+        let efs = EasyFileSystem::open(Arc::clone(&block_device)).unwrap();
+        let first = get_block_cache(block_id, block_device)
+            .unwrap()
+            .lock()
+            .read(0, |data: &[u8; BLOCK_SZ]| data[0])
+            .unwrap();
+        assert_eq!(first, 0);
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn read_only_open_replays_journal_in_cache() {
+        let (device, efs) = create_fs(4096, FEATURE_CHECKSUMS);
+        let image = device.image();
+        device.start_recording();
+        Inode::root_inode(&efs).create("file").unwrap();
+        let writes = device.take_writes();
+
+        // 在任意一次写入之后崩溃, 只读打开都能看到一致的内容, 并且不会写入设备
+        let mut created = false;
+        for count in 0..=writes.len() {
+            let crashed = Arc::new(MemDevice::from_image(MemDevice::crash_image(
+                &image, &writes, count,
+            )));
+            crashed.start_recording();
+            let efs = EasyFileSystem::open_read_only(crashed.clone()).unwrap();
+            let report = fsck(&efs, false).unwrap();
+            assert!(
+                report.is_clean(),
+                "crash after write {}: {:?}",
+                count,
+                report
+            );
+            let root = Inode::root_inode(&efs);
+            // 日志中已经提交的事务只重放到了块缓存中, 同样可以看到
+            let found = root.find("file").is_ok();
+            assert!(found || !created);
+            created = found;
+            assert_eq!(root.create("other").err(), Some(EfsError::ReadOnly));
+            assert_eq!(fsck(&efs, true).err(), Some(EfsError::ReadOnly));
+            assert!(crashed.take_writes().is_empty());
+        }
+        assert!(created);
+    }
+
+    #[test]
+    fn failed_write_leaves_consistent_volume() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let file = root.create("file").unwrap();
+        let data = vec![5u8; 5000 * BLOCK_SZ];
+        assert_eq!(file.write_at(0, &data), Err(EfsError::NoSpace));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        // 之前提交的批次仍然保留, 文件系统可以继续使用
+        let size = file.stat().unwrap().size as usize;
+        assert!(size > 0 && size.is_multiple_of(BLOCK_SZ));
+        root.unlink("file").unwrap();
+        root.create("other").unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn grow_with_checksums_beyond_initial_table() {
+        let block_device: Arc<dyn BlockDevice> = Arc::new(MemDevice::new(30000));
+        let efs =
+            EasyFileSystem::create_with_features(block_device, 4200, 1, FEATURE_CHECKSUMS).unwrap();
+        let file = Inode::root_inode(&efs).create("file").unwrap();
+        file.write_at(0, &[3u8; 100 * BLOCK_SZ]).unwrap();
+        efs.lock().grow(30000).unwrap();
+        assert_eq!(efs.lock().total_blocks().unwrap(), 30000);
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        // 新增的块可以被分配使用
+        file.write_at(0, &vec![4u8; 8000 * BLOCK_SZ]).unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn shrink_relocates_blocks_out_of_the_tail() {
+        let (device, efs) = create_fs(8192, 0);
+        let root = Inode::root_inode(&efs);
+        root.create("a")
+            .unwrap()
+            .write_at(0, &vec![1u8; 3000 * BLOCK_SZ])
+            .unwrap();
+        let data: Vec<u8> = (0..1000 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+        root.create("b").unwrap().write_at(0, &data).unwrap();
+        root.unlink("a").unwrap();
+        drop(root);
+
+        // b的块位于a之后, 缩小之后必须搬移到a留下的空闲块中
+        let new_total_blocks = efs.lock().data_area_start_block + 2000;
+        assert_eq!(efs.lock().shrink(8193), Err(EfsError::InvalidArgument));
+        assert_eq!(
+            efs.lock().shrink(new_total_blocks - 1500),
+            Err(EfsError::NoSpace)
+        );
+        efs.lock().shrink(new_total_blocks).unwrap();
+        assert_eq!(efs.lock().total_blocks(), Ok(new_total_blocks as usize));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        drop(efs);
+
+        // 截断之后的镜像可以直接打开
+        let image = device.image()[..new_total_blocks as usize * BLOCK_SZ].to_vec();
+        let efs = EasyFileSystem::open(Arc::new(MemDevice::from_image(image))).unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        let mut buf = vec![0u8; data.len()];
+        let b = Inode::root_inode(&efs).find("b").unwrap();
+        assert_eq!(b.read_at(0, &mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+    }
+}
@@ -0,0 +1,67 @@
+use core::fmt;
+
+/// easy-fs操作失败的原因
+///
+/// 所有公开的操作都通过Result返回错误, 由内核将它们转换为系统调用的错误码
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EfsError {
+    // 没有空闲的inode或者数据块
+    NoSpace,
+    // 文件、目录或者扩展属性不存在
+    NotFound,
+    // 文件已经存在
+    Exists,
+    // 磁盘上的数据已经损坏, 例如校验和不一致或者位图与inode不一致
+    Corrupted,
+    // 路径中的某一部分不是目录
+    NotDir,
+    // 对目录进行了不能对目录进行的操作
+    IsDir,
+    // 删除的目录不是空目录
+    NotEmpty,
+    // 文件名或者扩展属性名过长
+    NameTooLong,
+    // 文件名不合法, 例如为空、为"."或者包含'/'
+    InvalidName,
+    // 路径查找时跟随的符号链接次数过多
+    TooManyLinks,
+    // 超过了文件的最大大小
+    FileTooLarge,
+    // 参数不合法, 例如对不是符号链接的inode读取链接目标
+    InvalidArgument,
+    // 镜像不支持这个操作, 例如版本1的镜像中没有权限和扩展属性
+    Unsupported,
+    // 块设备读写失败
+    IoError,
+    // 文件系统以只读方式打开, 不能进行修改
+    ReadOnly,
+    // 一次事务修改的块超过了日志的容量
+    TransactionTooLarge,
+}
+
+impl fmt::Display for EfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::NoSpace => "no space left on device",
+            Self::NotFound => "no such file or directory",
+            Self::Exists => "file exists",
+            Self::Corrupted => "file system is corrupted",
+            Self::NotDir => "not a directory",
+            Self::IsDir => "is a directory",
+            Self::NotEmpty => "directory not empty",
+            Self::NameTooLong => "name too long",
+            Self::InvalidName => "invalid name",
+            Self::TooManyLinks => "too many levels of symbolic links",
+            Self::FileTooLarge => "file too large",
+            Self::InvalidArgument => "invalid argument",
+            Self::Unsupported => "operation not supported",
+            Self::IoError => "input/output error",
+            Self::ReadOnly => "read-only file system",
+            Self::TransactionTooLarge => "transaction too large for the journal",
+        };
+        f.write_str(message)
+    }
+}
+
+/// easy-fs操作的结果
+pub type Result<T> = core::result::Result<T, EfsError>;
@@ -0,0 +1,423 @@
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    block_cache::get_block_cache,
+    dir_index::{self, DIRENTS_PER_BLOCK},
+    efs::EasyFileSystem,
+    layout::{DirEntry, DiskInode, SuperBlock, DIRENT_SZ},
+    EfsError, Result, BLOCK_SZ,
+};
+
+/// 修复时每处理这么多个问题提交一次, 以免一次事务超过日志的容量
+const REPAIR_BATCH: usize = 32;
+
+/// 文件系统检查的结果
+#[derive(Default, Debug)]
+pub struct FsckReport {
+    // 位图中已分配但从根目录不可达的inode
+    pub leaked_inodes: Vec<u32>,
+    // 从根目录可达但位图中没有分配的inode
+    pub unallocated_inodes: Vec<u32>,
+    // 位图中已分配但没有被任何inode使用的数据块
+    pub leaked_blocks: Vec<u32>,
+    // 被inode使用但位图中没有分配的数据块
+    pub unallocated_blocks: Vec<u32>,
+    // 同时被多个inode使用的数据块, 无法自动修复
+    pub doubly_owned_blocks: Vec<u32>,
+    // inode中指向数据区域之外的块, (inode编号, 块编号), 无法自动修复
+    pub bad_blocks: Vec<(u32, u32)>,
+    // 指向未分配inode的目录项, (所在目录的inode编号, 文件名)
+    pub dangling_entries: Vec<(u32, String)>,
+    // 哈希目录中位于探测范围之外而无法被查找到的目录项, (所在目录的inode编号, 文件名)
+    pub misplaced_entries: Vec<(u32, String)>,
+    // 硬链接计数错误的inode, (inode编号, 记录的计数, 实际的计数)
+    pub bad_link_counts: Vec<(u32, u32, u32)>,
+    // 超级块中与inode位图不一致的空闲inode数量, (记录的数量, 实际的数量)
+    pub bad_free_inodes: Option<(u32, u32)>,
+    // 超级块中与数据位图不一致的空闲数据块数量, (记录的数量, 实际的数量)
+    pub bad_free_data_blocks: Option<(u32, u32)>,
+    // 内容与校验和表不一致的块
+    pub bad_checksums: Vec<u32>,
+    // 引用计数与实际的所有者数量不一致的数据块, (块编号, 记录的计数, 实际的计数)
+    pub bad_refcounts: Vec<(u32, u32, u32)>,
+}
+
+impl FsckReport {
+    /// 文件系统是否没有任何问题
+    pub fn is_clean(&self) -> bool {
+        self.leaked_inodes.is_empty()
+            && self.unallocated_inodes.is_empty()
+            && self.leaked_blocks.is_empty()
+            && self.unallocated_blocks.is_empty()
+            && self.doubly_owned_blocks.is_empty()
+            && self.bad_blocks.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.misplaced_entries.is_empty()
+            && self.bad_link_counts.is_empty()
+            && self.bad_free_inodes.is_none()
+            && self.bad_free_data_blocks.is_none()
+            && self.bad_checksums.is_empty()
+            && self.bad_refcounts.is_empty()
+    }
+}
+
+/// 离线检查文件系统
+///
+/// 从根目录开始遍历所有可达的inode, 重新计算inode位图和数据位图中应该被分配的位,
+/// 并与磁盘上的位图进行比较. repair为true时会修复能够自动修复的问题:
+/// 删除悬空的目录项、修正硬链接计数、回收泄漏的inode和数据块、补上缺失的分配位,
+/// 以及重建含有无法被查找到的目录项的哈希目录
+///
+/// 存在快照时快照中的文件系统树以及快照自身使用的块同样计入数据块的所有者,
+/// 并检查每个数据块的引用计数是否等于所有者数量减一
+///
+/// 开启了校验和时首先检查所有块的校验和, 发现损坏的块时不再继续检查, repair为true时同样如此:
+/// 以损坏的内容重新计算校验和会让损坏再也无法被发现, 需要确认这些块的内容之后
+/// 通过EasyFileSystem::accept_checksums显式接受, 之后再检查和修复其余的问题
+///
+/// 检查过程中读写块设备失败时返回错误;
+/// 文件系统以只读方式打开时只能检查, repair为true时返回EfsError::ReadOnly
+pub fn fsck(efs: &Arc<Mutex<EasyFileSystem>>, repair: bool) -> Result<FsckReport> {
+    let mut fs = efs.lock();
+    if repair && fs.is_read_only() {
+        return Err(EfsError::ReadOnly);
+    }
+    let result = check(&mut fs, repair);
+    if result.is_err() {
+        // 修复到一半出错时放弃还没有提交的修改
+        fs.abort()?;
+    }
+    result
+}
+
+/// 在持有文件系统的锁时进行检查和修复
+fn check(fs: &mut MutexGuard<EasyFileSystem>, repair: bool) -> Result<FsckReport> {
+    let block_device = Arc::clone(&fs.block_device);
+    let mut report = FsckReport::default();
+    if fs.has_checksums() {
+        report.bad_checksums = fs.find_bad_checksums()?;
+        if !report.bad_checksums.is_empty() {
+            // 损坏的块在载入时会返回错误, 无法继续检查
+            return Ok(report);
+        }
+    }
+    let data_area_blocks = get_block_cache(0, Arc::clone(&block_device))?
+        .lock()
+        .read(0, |super_block: &SuperBlock| super_block.data_area_blocks)?;
+    let data_area_start_block = fs.get_data_block_id(0);
+    let inode_num = fs.inode_bitmap.maximum();
+
+    // 每个inode被目录项引用的次数
+    let mut refs = vec![0u32; inode_num];
+    let mut reachable = vec![false; inode_num];
+    // 每个数据块被多少个文件系统树引用
+    let mut owners = vec![0u32; data_area_blocks as usize];
+    // 需要删除的悬空目录项, (所在目录的inode编号, 目录项序号)
+    let mut dangling_slots: Vec<(u32, usize)> = Vec::new();
+    // 需要重建的哈希目录, (inode编号, 重建后至少需要的块数)
+    let mut misplaced_dirs: Vec<(u32, u32)> = Vec::new();
+
+    let mut queue: VecDeque<u32> = VecDeque::new();
+    queue.push_back(0);
+    reachable[0] = true;
+    while let Some(inode_id) = queue.pop_front() {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        let (blocks, dirents, buckets) =
+            get_block_cache(block_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| -> Result<_> {
+                    let mut dirents: Vec<DirEntry> = Vec::new();
+                    if disk_inode.is_dir() {
+                        for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                            let mut dirent = DirEntry::empty();
+                            disk_inode.read_at(
+                                i * DIRENT_SZ,
+                                dirent.as_bytes_mut(),
+                                &block_device,
+                            )?;
+                            dirents.push(dirent);
+                        }
+                    }
+                    // 大小不是块大小整数倍的哈希目录中所有的目录项都无法被查找到
+                    let buckets = disk_inode.is_hashed().then(|| {
+                        let size = disk_inode.size as usize;
+                        if size.is_multiple_of(BLOCK_SZ) {
+                            (size / BLOCK_SZ) as u32
+                        } else {
+                            0
+                        }
+                    });
+                    Ok((disk_inode.blocks(&block_device)?, dirents, buckets))
+                })??;
+        // 扩展属性块同样属于这个inode
+        let xattr_block = fs
+            .read_inode_meta(inode_id, |meta| meta.xattr_block)?
+            .unwrap_or(0);
+        let blocks = blocks
+            .into_iter()
+            .chain((xattr_block != 0).then_some(xattr_block));
+
+        for block_id in blocks {
+            if block_id < data_area_start_block
+                || block_id >= data_area_start_block + data_area_blocks
+            {
+                report.bad_blocks.push((inode_id, block_id));
+                continue;
+            }
+            let bit = (block_id - data_area_start_block) as usize;
+            if owners[bit] > 0 {
+                report.doubly_owned_blocks.push(block_id);
+            }
+            owners[bit] = 1;
+        }
+
+        for (slot, dirent) in dirents.iter().enumerate() {
+            if let Some(buckets) = buckets {
+                let block = (slot / DIRENTS_PER_BLOCK) as u32;
+                if !dirent.name().is_empty()
+                    && (buckets == 0
+                        || !dir_index::probe_blocks(dirent.name(), buckets).any(|b| b == block))
+                {
+                    report
+                        .misplaced_entries
+                        .push((inode_id, String::from(dirent.name())));
+                    if misplaced_dirs.last().map(|&(dir, _)| dir) != Some(inode_id) {
+                        misplaced_dirs.push((inode_id, buckets));
+                    }
+                }
+            }
+            if matches!(dirent.name(), "" | "." | "..") {
+                continue;
+            }
+            let child = dirent.inode_number() as usize;
+            if child >= inode_num || !fs.inode_bitmap.is_allocated(&block_device, child)? {
+                report
+                    .dangling_entries
+                    .push((inode_id, String::from(dirent.name())));
+                dangling_slots.push((inode_id, slot));
+                continue;
+            }
+            refs[child] += 1;
+            if !reachable[child] {
+                reachable[child] = true;
+                queue.push_back(child as u32);
+            }
+        }
+    }
+
+    // 快照表、引用计数文件以及快照镜像只属于快照自身
+    let in_data_area = |block_id: u32| {
+        (data_area_start_block..data_area_start_block + data_area_blocks).contains(&block_id)
+    };
+    for block_id in fs.snapshot_metadata_blocks()? {
+        if !in_data_area(block_id) {
+            continue;
+        }
+        let bit = (block_id - data_area_start_block) as usize;
+        if owners[bit] > 0 {
+            report.doubly_owned_blocks.push(block_id);
+        }
+        owners[bit] += 1;
+    }
+    // 每个快照中的文件系统树与当前的文件系统树共享数据块, 同一棵树中的重复引用只计一次,
+    // 指向数据区域之外的块在当前的文件系统树中已经检查过
+    for (_, image) in fs.snapshot_images()? {
+        let mut blocks = fs.tree_blocks(Some(&image))?;
+        blocks.sort_unstable();
+        blocks.dedup();
+        for block_id in blocks
+            .into_iter()
+            .filter(|&block_id| in_data_area(block_id))
+        {
+            owners[(block_id - data_area_start_block) as usize] += 1;
+        }
+    }
+
+    // 比较inode位图, 并检查硬链接计数
+    for (inode_id, &is_reachable) in reachable.iter().enumerate() {
+        let allocated = fs.inode_bitmap.is_allocated(&block_device, inode_id)?;
+        if allocated && !is_reachable {
+            report.leaked_inodes.push(inode_id as u32);
+        } else if !allocated && is_reachable {
+            report.unallocated_inodes.push(inode_id as u32);
+        }
+        // 根目录没有目录项指向它
+        if !is_reachable || inode_id == 0 {
+            continue;
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id as u32);
+        let nlink = get_block_cache(block_id as usize, Arc::clone(&block_device))?
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.nlink)?;
+        if nlink != refs[inode_id] {
+            report
+                .bad_link_counts
+                .push((inode_id as u32, nlink, refs[inode_id]));
+        }
+    }
+
+    // 比较数据位图
+    for (bit, &count) in owners.iter().enumerate() {
+        let owned = count > 0;
+        let allocated = fs.data_bitmap.is_allocated(&block_device, bit)?;
+        if allocated && !owned {
+            report
+                .leaked_blocks
+                .push(bit as u32 + data_area_start_block);
+        } else if !allocated && owned {
+            report
+                .unallocated_blocks
+                .push(bit as u32 + data_area_start_block);
+        }
+    }
+
+    // 比较引用计数, 没有快照时所有的块都只有一个所有者
+    if fs.has_snapshots() {
+        for (bit, &count) in owners.iter().enumerate() {
+            let block_id = bit as u32 + data_area_start_block;
+            let recorded = fs.refcount(block_id)? as u32;
+            let actual = count.saturating_sub(1);
+            if recorded != actual {
+                report.bad_refcounts.push((block_id, recorded, actual));
+            }
+        }
+    }
+
+    // 比较超级块中的空闲计数
+    let free_inodes = fs.inode_bitmap.count_free(&block_device)? as u32;
+    let recorded_free_inodes = fs.free_inodes()?;
+    if recorded_free_inodes != free_inodes {
+        report.bad_free_inodes = Some((recorded_free_inodes, free_inodes));
+    }
+    let free_data_blocks = fs.data_bitmap.count_free(&block_device)? as u32;
+    let recorded_free_data_blocks = fs.free_data_blocks()?;
+    if recorded_free_data_blocks != free_data_blocks {
+        report.bad_free_data_blocks = Some((recorded_free_data_blocks, free_data_blocks));
+    }
+
+    if repair {
+        // 先修正引用计数, 之后修改共享的块以及回收泄漏的块时才能得到正确的结果
+        for (i, &(block_id, _, actual)) in report.bad_refcounts.iter().enumerate() {
+            fs.set_refcount(block_id, actual.min(u8::MAX as u32) as u8)?;
+            if (i + 1) % REPAIR_BATCH == 0 {
+                fs.commit()?;
+            }
+        }
+        fs.commit()?;
+        for &(dir_inode_id, slot) in dangling_slots.iter() {
+            let (block_id, block_offset) = fs.get_disk_inode_pos(dir_inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    fs.unshare(disk_inode, slot * DIRENT_SZ, DIRENT_SZ)?;
+                    disk_inode.write_at(
+                        slot * DIRENT_SZ,
+                        DirEntry::empty().as_bytes(),
+                        &block_device,
+                    )
+                })??;
+            fs.commit()?;
+        }
+        for &(inode_id, _, actual) in report.bad_link_counts.iter() {
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&block_device))?
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.nlink = actual;
+                })?;
+            fs.commit()?;
+        }
+        for (i, &inode_id) in report.leaked_inodes.iter().enumerate() {
+            fs.dealloc_inode(inode_id)?;
+            if (i + 1) % REPAIR_BATCH == 0 {
+                fs.commit()?;
+            }
+        }
+        for &inode_id in report.unallocated_inodes.iter() {
+            fs.inode_bitmap
+                .set_allocated(&block_device, inode_id as usize, true)?;
+        }
+        fs.commit()?;
+        for (i, &block_id) in report.leaked_blocks.iter().enumerate() {
+            fs.dealloc_data(block_id)?;
+            if (i + 1) % REPAIR_BATCH == 0 {
+                fs.commit()?;
+            }
+        }
+        for &block_id in report.unallocated_blocks.iter() {
+            fs.data_bitmap.set_allocated(
+                &block_device,
+                (block_id - data_area_start_block) as usize,
+                true,
+            )?;
+        }
+        fs.commit()?;
+        // 重建哈希目录时需要分配新的块, 因此在位图修复完成之后进行
+        for &(dir_inode_id, buckets) in misplaced_dirs.iter() {
+            fs.rebuild_dir_index(dir_inode_id, buckets)?;
+        }
+        // 位图修复完成之后重新计算空闲计数
+        fs.recount_free()?;
+        fs.commit()?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use super::fsck;
+    use crate::{
+        block_dev::BlockDevice,
+        efs::EasyFileSystem,
+        layout::FEATURE_CHECKSUMS,
+        test_util::{create_fs, MemDevice},
+        vfs::Inode,
+        BLOCK_SZ,
+    };
+
+    #[test]
+    fn leaked_block_is_found_and_freed() {
+        let (_, efs) = create_fs(4096, 0);
+        let free = efs.lock().free_data_blocks().unwrap();
+        let leaked = {
+            let mut fs = efs.lock();
+            let block_id = fs.alloc_data().unwrap();
+            fs.commit().unwrap();
+            block_id
+        };
+        let report = fsck(&efs, false).unwrap();
+        assert_eq!(report.leaked_blocks, [leaked]);
+        assert!(!report.is_clean());
+
+        fsck(&efs, true).unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        assert_eq!(efs.lock().free_data_blocks().unwrap(), free);
+    }
+
+    #[test]
+    fn bad_checksum_is_reported_until_accepted() {
+        let (device, efs) = create_fs(4096, FEATURE_CHECKSUMS);
+        Inode::root_inode(&efs).create("file").unwrap();
+        let (inode_block, _) = efs.lock().get_disk_inode_pos(0);
+
+        // 修改inode块末尾没有被使用的字节, 块的内容不再与校验和一致
+        let mut image = device.image();
+        image[(inode_block as usize + 1) * BLOCK_SZ - 1] ^= 0xff;
+        let corrupted: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(image));
+        let efs = EasyFileSystem::open(Arc::clone(&corrupted)).unwrap();
+        let report = fsck(&efs, false).unwrap();
+        assert_eq!(report.bad_checksums, [inode_block]);
+
+        // 修复时不会以损坏的内容重新计算校验和
+        assert_eq!(fsck(&efs, true).unwrap().bad_checksums, [inode_block]);
+        assert!(Inode::root_inode(&efs).find("file").is_err());
+
+        // 确认块的内容之后显式接受
+        efs.lock().accept_checksums(&report.bad_checksums).unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        Inode::root_inode(&efs).find("file").unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+use alloc::sync::Arc;
+
+use crate::{
+    block_cache::{
+        block_cache_dirty_list, block_cache_update_checksums, get_block_cache,
+        get_zeroed_block_cache,
+    },
+    block_dev::BlockDevice,
+    EfsError, Result, BLOCK_SZ,
+};
+
+/// 描述块的魔数
+const JOURNAL_DESC_MAGIC: u32 = 0x4a4e_4c44;
+/// 提交块的魔数
+const JOURNAL_COMMIT_MAGIC: u32 = 0x4a4e_4c43;
+/// 一个描述块中最多可以记录的块编号数量
+const JOURNAL_DESC_ENTRIES: usize = BLOCK_SZ / 4 - 3;
+
+/// 磁盘块上的数据
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 日志区域的描述块, 位于日志区域的第一个块
+///
+/// 记录本次事务中的块在磁盘上原本的位置, 这些块的副本依次存放在描述块之后
+#[repr(C)]
+struct JournalDescriptor {
+    // 为JOURNAL_DESC_MAGIC时表示日志中有尚未完成写回的事务
+    magic: u32,
+    // 事务序号, 用于和提交块进行匹配
+    sequence: u32,
+    // 本次事务中块的数量
+    count: u32,
+    // 每个块在磁盘上的块编号
+    block_ids: [u32; JOURNAL_DESC_ENTRIES],
+}
+
+/// 日志区域的提交块, 紧跟在最后一个块副本之后
+///
+/// 只有提交块写入磁盘之后事务才算完成, 崩溃后才会被重放
+#[repr(C)]
+struct JournalCommit {
+    magic: u32,
+    sequence: u32,
+}
+
+/// 预写日志
+///
+/// 一次事务由块缓存中所有被修改过的块组成, 按照以下顺序写入磁盘:
+/// 1. 描述块和所有块的副本写入日志区域
+/// 2. 写入提交块, 此时事务已经持久化
+/// 3. 将块缓存写回到它们原本的位置
+/// 4. 清除描述块, 表示日志中的事务已经全部完成
+///
+/// 在第2步和第4步之间崩溃时, 打开文件系统会根据日志重放事务,
+/// 因此一次事务中的修改要么全部生效, 要么全部不生效
+pub struct Journal {
+    // 日志区域的起始块编号
+    start_block: usize,
+    // 日志区域的块数
+    blocks: usize,
+    // 下一个事务的序号
+    sequence: u32,
+}
+
+impl Journal {
+    pub fn new(start_block: usize, blocks: usize) -> Self {
+        Self {
+            start_block,
+            blocks,
+            sequence: 0,
+        }
+    }
+
+    /// 日志区域的块数
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    /// 一次事务最多可以包含的块数量
+    ///
+    /// 日志区域需要留出描述块和提交块的位置
+    fn capacity(&self) -> usize {
+        (self.blocks - 2).min(JOURNAL_DESC_ENTRIES)
+    }
+
+    /// 提交块缓存中所有被修改过的块
+    ///
+    /// 所有被修改的块作为一次事务提交, 超过日志容量时不会写入任何块, 返回EfsError::TransactionTooLarge,
+    /// 需要修改更多块的操作应该拆分为多个各自完整的事务;
+    /// 开启了校验和时先更新校验和表, 让块和它们的校验和在同一次提交中写入;
+    /// 写入失败时提交块可能还没有写入, 这个事务在下次打开时会被丢弃
+    pub fn commit(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        block_cache_update_checksums(block_device)?;
+        let transaction = block_cache_dirty_list(block_device);
+        if transaction.is_empty() {
+            return Ok(());
+        }
+        if transaction.len() > self.capacity() {
+            return Err(EfsError::TransactionTooLarge);
+        }
+        let mut desc = JournalDescriptor {
+            magic: JOURNAL_DESC_MAGIC,
+            sequence: self.sequence,
+            count: transaction.len() as u32,
+            block_ids: [0; JOURNAL_DESC_ENTRIES],
+        };
+        // 写入描述块以及块的副本
+        for (i, (block_id, cache)) in transaction.iter().enumerate() {
+            desc.block_ids[i] = *block_id as u32;
+            cache.lock().read(0, |data_block: &DataBlock| {
+                block_device.write_block(self.start_block + 1 + i, data_block)
+            })??;
+        }
+        self.write_struct(block_device, 0, &desc)?;
+        block_device.flush()?;
+        // 写入提交块
+        let commit = JournalCommit {
+            magic: JOURNAL_COMMIT_MAGIC,
+            sequence: self.sequence,
+        };
+        self.write_struct(block_device, 1 + transaction.len(), &commit)?;
+        block_device.flush()?;
+        // 将块写回原本的位置
+        for (_, cache) in transaction.iter() {
+            cache.lock().sync()?;
+        }
+        self.clear(block_device)
+    }
+
+    /// 读出日志中的描述块, 并判断其中的事务是否已经提交
+    ///
+    /// 日志中没有事务时返回None; 之后的事务序号接着描述块中的序号递增
+    fn pending(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Option<(JournalDescriptor, bool)>> {
+        let desc: JournalDescriptor = self.read_struct(block_device, 0)?;
+        self.sequence = desc.sequence.wrapping_add(1);
+        if desc.magic != JOURNAL_DESC_MAGIC || desc.count as usize > self.capacity() {
+            return Ok(None);
+        }
+        let commit: JournalCommit = self.read_struct(block_device, 1 + desc.count as usize)?;
+        let committed = commit.magic == JOURNAL_COMMIT_MAGIC && commit.sequence == desc.sequence;
+        Ok(Some((desc, committed)))
+    }
+
+    /// 打开文件系统时重放已经提交但还没有完成写回的事务
+    pub fn replay(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let desc = match self.pending(block_device)? {
+            None => return Ok(()),
+            // 事务没有提交, 直接丢弃
+            Some((_, false)) => return self.clear(block_device),
+            Some((desc, true)) => desc,
+        };
+        let mut data = [0u8; BLOCK_SZ];
+        for (i, block_id) in desc.block_ids[..desc.count as usize].iter().enumerate() {
+            block_device.read_block(self.start_block + 1 + i, &mut data)?;
+            // 通过块缓存写回, 保证缓存中的内容与磁盘一致
+            let cache = get_block_cache(*block_id as usize, Arc::clone(block_device))?;
+            let mut cache = cache.lock();
+            cache.modify(0, |data_block: &mut DataBlock| {
+                data_block.copy_from_slice(&data);
+            })?;
+            cache.sync()?;
+        }
+        self.clear(block_device)
+    }
+
+    /// 只读打开文件系统时使用, 将已经提交的事务只重放到块缓存中
+    ///
+    /// 事务中的块在缓存中成为被修改过的块, 不会被换出, 也不会写入块设备, 日志本身保持不变;
+    /// 块不从磁盘载入, 因此磁盘上写回到一半的内容不会被校验
+    pub fn load(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let desc = match self.pending(block_device)? {
+            Some((desc, true)) => desc,
+            _ => return Ok(()),
+        };
+        let mut data = [0u8; BLOCK_SZ];
+        for (i, block_id) in desc.block_ids[..desc.count as usize].iter().enumerate() {
+            block_device.read_block(self.start_block + 1 + i, &mut data)?;
+            get_zeroed_block_cache(*block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block.copy_from_slice(&data);
+                })?;
+        }
+        Ok(())
+    }
+
+    /// 清除描述块, 保留事务序号以便下次打开时继续递增
+    fn clear(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let desc = JournalDescriptor {
+            magic: 0,
+            sequence: self.sequence,
+            count: 0,
+            block_ids: [0; JOURNAL_DESC_ENTRIES],
+        };
+        self.write_struct(block_device, 0, &desc)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// 将结构体直接写入日志区域的第offset个块, 不经过块缓存
+    fn write_struct<T>(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        offset: usize,
+        value: &T,
+    ) -> Result<()> {
+        let mut data = [0u8; BLOCK_SZ];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                value as *const T as *const u8,
+                data.as_mut_ptr(),
+                core::mem::size_of::<T>(),
+            );
+        }
+        block_device.write_block(self.start_block + offset, &data)
+    }
+
+    /// 从日志区域的第offset个块中直接读取结构体, 不经过块缓存
+    fn read_struct<T>(&self, block_device: &Arc<dyn BlockDevice>, offset: usize) -> Result<T> {
+        let mut data = [0u8; BLOCK_SZ];
+        block_device.read_block(self.start_block + offset, &mut data)?;
+        Ok(unsafe { core::ptr::read_unaligned(data.as_ptr() as *const T) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use super::Journal;
+    use crate::{
+        block_cache::get_block_cache,
+        block_dev::BlockDevice,
+        efs::EasyFileSystem,
+        fsck::fsck,
+        layout::FEATURE_CHECKSUMS,
+        test_util::{create_fs, MemDevice},
+        vfs::Inode,
+        EfsError, BLOCK_SZ,
+    };
+
+    /// 读出设备上第block_id个块的第一个字节
+    fn first_byte(block_device: &Arc<dyn BlockDevice>, block_id: usize) -> u8 {
+        let mut data = [0u8; BLOCK_SZ];
+        block_device.read_block(block_id, &mut data).unwrap();
+        data[0]
+    }
+
+    #[test]
+    fn torn_commit_is_discarded_or_replayed() {
+        let device = Arc::new(MemDevice::new(64));
+        let block_device: Arc<dyn BlockDevice> = device.clone();
+        let blocks = 40..44;
+        for block_id in blocks.clone() {
+            block_device
+                .write_block(block_id, &[1u8; BLOCK_SZ])
+                .unwrap();
+        }
+        let image = device.image();
+        for block_id in blocks.clone() {
+            get_block_cache(block_id, Arc::clone(&block_device))
+                .unwrap()
+                .lock()
+                .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(2))
+                .unwrap();
+        }
+        device.start_recording();
+        Journal::new(1, 16).commit(&block_device).unwrap();
+        let writes = device.take_writes();
+
+        // 在任意一次写入之后崩溃, 重放之后的块要么全部是旧的内容, 要么全部是新的内容
+        let mut replayed = false;
+        for count in 0..=writes.len() {
+            let crashed: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(
+                MemDevice::crash_image(&image, &writes, count),
+            ));
+            Journal::new(1, 16).replay(&crashed).unwrap();
+            let contents: Vec<u8> = blocks
+                .clone()
+                .map(|block_id| first_byte(&crashed, block_id))
+                .collect();
+            assert!(contents == vec![1; 4] || contents == vec![2; 4]);
+            // 提交块写入之后的崩溃都能得到新的内容
+            assert!(!replayed || contents == vec![2; 4]);
+            replayed = contents == vec![2; 4];
+        }
+        assert!(replayed);
+    }
+
+    #[test]
+    fn commit_larger_than_journal_is_refused() {
+        // 被拒绝的事务中的块仍然是脏的, 使用文件系统数据区域中的块, 文件系统释放时它们随之被丢弃
+        let (device, efs) = create_fs(4096, 0);
+        let block_device = Arc::clone(&efs.lock().block_device);
+        let start = efs.lock().get_data_block_id(0) as usize;
+        for block_id in start..start + 20 {
+            get_block_cache(block_id, Arc::clone(&block_device))
+                .unwrap()
+                .lock()
+                .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(3))
+                .unwrap();
+        }
+        device.start_recording();
+        assert_eq!(
+            Journal::new(1, 16).commit(&block_device),
+            Err(EfsError::TransactionTooLarge)
+        );
+        assert!(device.take_writes().is_empty());
+    }
+
+    /// 在一次很大的写入过程中的每一次块写入之后崩溃, 重新打开的文件系统都能通过检查
+    fn crash_during_large_write(features: u32) {
+        let (device, efs) = create_fs(4096, features);
+        let file = Inode::root_inode(&efs).create("file").unwrap();
+        file.write_at(0, &[1u8; 50 * BLOCK_SZ]).unwrap();
+        let image = device.image();
+        device.start_recording();
+        let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+        file.write_at(0, &data).unwrap();
+        let writes = device.take_writes();
+
+        for count in 0..=writes.len() {
+            let crashed: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(
+                MemDevice::crash_image(&image, &writes, count),
+            ));
+            let efs = EasyFileSystem::open(Arc::clone(&crashed)).unwrap();
+            let report = fsck(&efs, false).unwrap();
+            assert!(
+                report.is_clean(),
+                "crash after write {}: {:?}",
+                count,
+                report
+            );
+            if count == writes.len() {
+                let file = Inode::root_inode(&efs).find("file").unwrap();
+                let mut buf = vec![0u8; data.len()];
+                assert_eq!(file.read_at(0, &mut buf).unwrap(), data.len());
+                assert!(buf == data);
+            }
+        }
+    }
+
+    #[test]
+    fn crash_during_large_write_is_consistent() {
+        crash_during_large_write(0);
+    }
+
+    #[test]
+    fn crash_during_large_write_with_checksums_is_consistent() {
+        crash_during_large_write(FEATURE_CHECKSUMS);
+    }
+}
@@ -1,5 +1,85 @@
-use crate::EFS_MAGIX;
+use alloc::{sync::Arc, vec, vec::Vec};
 
+use crate::{
+    block_cache::CHECKSUMS_PER_BLOCK, block_dev::BlockDevice, get_block_cache, EfsError, Result,
+    BLOCK_BITS, BLOCK_SZ, EFS_MAGIX, EFS_VERSION,
+};
+
+/// 直接索引的数量
+///
+/// 让DiskInode的大小恰好为128字节, 一个块中可以放下4个DiskInode
+const INODE_DIRECT_COUNT: usize = 27;
+/// 一级间接索引块中可以容纳的索引数量
+const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+/// 二级间接索引可以容纳的索引数量
+const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// 直接索引可以表示的数据块上界
+const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
+/// 一级间接索引可以表示的数据块上界
+const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
+/// 二级间接索引可以表示的数据块上界
+const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+
+/// 直接保存在DiskInode中的extent数量
+///
+/// direct的第一个u32保存extent的数量, 剩下的26个u32可以放下13个extent
+const INODE_INLINE_EXTENTS: usize = (INODE_DIRECT_COUNT - 1) / 2;
+/// 一个extent块中可以容纳的extent数量
+const EXTENTS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<Extent>();
+/// 直接保存的extent以及一级extent块可以表示的extent数量上界
+const EXTENT1_BOUND: usize = INODE_INLINE_EXTENTS + EXTENTS_PER_BLOCK;
+/// 二级extent索引块可以表示的extent数量上界
+const EXTENT2_BOUND: usize = EXTENT1_BOUND + INODE_INDIRECT1_COUNT * EXTENTS_PER_BLOCK;
+
+/// 超级块特性: 新建的inode使用extent记录数据块
+pub const FEATURE_EXTENTS: u32 = 1;
+/// 文件系统特性: 元数据块和数据块带有CRC32校验和, 存放在日志区域之后的校验和表中
+pub const FEATURE_CHECKSUMS: u32 = 2;
+/// 文件系统特性: 新建的文件和符号链接将较小的内容直接保存在DiskInode中
+pub const FEATURE_INLINE_DATA: u32 = 4;
+/// 文件系统特性: 存在快照, 数据块可能被多个文件系统树共享, 修改之前需要先复制
+///
+/// 在创建第一个快照时设置, 删除最后一个快照时清除, 不认识这个特性的实现不会打开镜像
+pub const FEATURE_SNAPSHOTS: u32 = 8;
+/// 文件系统特性: 新建的普通文件以压缩的形式保存内容
+pub const FEATURE_COMPRESSION: u32 = 16;
+/// 文件系统特性: 目录项较多的目录改为按照文件名的哈希值排列
+pub const FEATURE_DIR_INDEX: u32 = 32;
+/// 当前支持的所有文件系统特性
+const FEATURE_SUPPORTED: u32 = FEATURE_EXTENTS
+    | FEATURE_CHECKSUMS
+    | FEATURE_INLINE_DATA
+    | FEATURE_SNAPSHOTS
+    | FEATURE_COMPRESSION
+    | FEATURE_DIR_INDEX;
+/// inode标志: 数据块通过extent而不是块索引进行定位
+pub const INODE_FLAG_EXTENTS: u16 = 1;
+/// inode标志: 文件内容直接保存在direct、indirect1和indirect2所占的空间中
+pub const INODE_FLAG_INLINE: u16 = 2;
+/// inode标志: 文件内容按块组压缩保存
+pub const INODE_FLAG_COMPRESSED: u16 = 4;
+/// inode标志: 目录项按照文件名的哈希值排列
+pub const INODE_FLAG_HASHED: u16 = 8;
+/// 压缩文件中每个块组占用的块编号数量
+pub const CHUNK_BLOCKS: u32 = 8;
+/// 压缩文件中每个块组的字节数, 每个块组独立压缩
+pub const COMPRESSED_CHUNK_SZ: usize = CHUNK_BLOCKS as usize * BLOCK_SZ;
+/// 可以直接保存在DiskInode中的文件内容的最大字节数
+pub const INLINE_DATA_LIMIT: usize = (INODE_DIRECT_COUNT + 2) * 4;
+/// 超级块状态: 空闲计数可能和位图不一致, 打开时需要根据位图重新计算
+pub const STATE_RECOUNT: u32 = 1;
+
+/// 目录项中文件名的最大长度
+const NAME_LENGTH_LIMIT: usize = 27;
+/// 目录项的大小
+pub const DIRENT_SZ: usize = 32;
+
+/// 间接索引块, 由128个u32的块编号组成
+type IndirectBlock = [u32; BLOCK_SZ / 4];
+/// 数据块
+type DataBlock = [u8; BLOCK_SZ];
+/// extent块, 由64个extent组成
+type ExtentBlock = [Extent; EXTENTS_PER_BLOCK];
 
 #[repr(C)]
 pub struct SuperBlock {
@@ -8,6 +88,8 @@ pub struct SuperBlock {
     // 文件系统的总块数,
     // 并不等于所占磁盘总块数,因为文件系统不一定占满整块磁盘
     pub total_blocks: u32,
+    // 日志区域块数, 日志区域紧跟在超级块之后
+    pub journal_blocks: u32,
     // easy-fs布局中后面四个连续区域有多少个块
     // inode位图块数
     pub inode_bitmap_blocks: u32,
@@ -17,22 +99,1575 @@ pub struct SuperBlock {
     pub data_bitmap_blocks: u32,
     // 数据区域块数
     pub data_area_blocks: u32,
+    // 空闲的inode数量
+    pub free_inodes: u32,
+    // 空闲的数据块数量
+    pub free_data_blocks: u32,
+    // 文件系统特性, 在格式化时确定
+    // 旧的镜像中这个位置为0, 即不开启任何特性
+    pub features: u32,
+    // 磁盘格式的版本, 旧的镜像中这个位置为0, 和版本1的格式相同
+    pub version: u32,
+    // 文件系统状态, 由STATE_*常量组合而成
+    pub state: u32,
+    // 校验和表块数, 校验和表紧跟在日志区域之后, 没有开启校验和时为0
+    pub checksum_blocks: u32,
+    // 快照表所在的数据块, 没有快照时为0
+    pub snapshot_table: u32,
 }
 
-
 impl SuperBlock {
-    pub fn initialize(&mut self, total_blocks: u32, inode_bitmap_blocks: u32, inode_area_blocks: u32, data_bitmap_blocks: u32, data_area_blocks: u32) {
+    pub fn initialize(
+        &mut self,
+        total_blocks: u32,
+        journal_blocks: u32,
+        inode_bitmap_blocks: u32,
+        inode_area_blocks: u32,
+        data_bitmap_blocks: u32,
+        data_area_blocks: u32,
+    ) {
         *self = Self {
             magic: EFS_MAGIX,
             total_blocks,
+            journal_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
-            data_area_blocks
+            data_area_blocks,
+            free_inodes: inode_bitmap_blocks * BLOCK_BITS as u32,
+            free_data_blocks: data_area_blocks,
+            features: 0,
+            version: EFS_VERSION,
+            state: 0,
+            checksum_blocks: 0,
+            snapshot_table: 0,
         }
     }
 
+    /// 魔数正确, 版本不高于当前支持的版本并且没有不认识的特性
+    ///
+    /// 同时检查各个区域的大小是否自洽, 避免损坏的超级块让之后的访问越界
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIX
+            && self.version <= EFS_VERSION
+            && self.features & !FEATURE_SUPPORTED == 0
+            && self.has_valid_layout()
+    }
+
+    /// 各个区域的大小是否和总块数以及位图的容量一致
+    fn has_valid_layout(&self) -> bool {
+        let used_blocks = 1
+            + self.journal_blocks as u64
+            + self.checksum_blocks as u64
+            + self.inode_bitmap_blocks as u64
+            + self.inode_area_blocks as u64
+            + self.data_bitmap_blocks as u64
+            + self.data_area_blocks as u64;
+        let inode_bytes =
+            self.inode_bitmap_blocks as u64 * BLOCK_BITS as u64 * self.inode_size() as u64;
+        let checksums_valid = if self.features & FEATURE_CHECKSUMS != 0 {
+            self.checksum_blocks as u64 * CHECKSUMS_PER_BLOCK as u64 >= self.total_blocks as u64
+        } else {
+            self.checksum_blocks == 0
+        };
+        // 快照表必须位于数据区域中, 并且和FEATURE_SNAPSHOTS同时存在
+        let data_area_start = used_blocks - self.data_area_blocks as u64;
+        let snapshots_valid = if self.features & FEATURE_SNAPSHOTS != 0 {
+            (data_area_start..used_blocks).contains(&(self.snapshot_table as u64))
+        } else {
+            self.snapshot_table == 0
+        };
+        // 日志区域至少要放下描述块、提交块以及一个块的副本
+        self.journal_blocks >= 3
+            && self.inode_bitmap_blocks > 0
+            && used_blocks <= self.total_blocks as u64
+            && inode_bytes <= self.inode_area_blocks as u64 * BLOCK_SZ as u64
+            && self.data_area_blocks as u64 <= self.data_bitmap_blocks as u64 * BLOCK_BITS as u64
+            && checksums_valid
+            && snapshots_valid
+    }
+
+    /// 每个inode在inode区域中占用的字节数
+    ///
+    /// 版本2开始在DiskInode之后紧跟着一个DiskInodeMeta
+    pub fn inode_size(&self) -> usize {
+        if self.version >= 2 {
+            core::mem::size_of::<DiskInode>() + core::mem::size_of::<DiskInodeMeta>()
+        } else {
+            core::mem::size_of::<DiskInode>()
+        }
+    }
+}
+
+/// 索引节点的类型
+///
+/// 在DiskInode中以u16保存, 读出时通过TryFrom<u16>解码, 不合法的值说明inode已经损坏
+#[derive(PartialEq, Clone, Copy, Debug)]
+#[repr(u16)]
+pub enum DiskInodeType {
+    File = 0,
+    Directory = 1,
+    // 符号链接, 数据中保存的是目标路径
+    SymLink = 2,
+}
+
+impl TryFrom<u16> for DiskInodeType {
+    type Error = EfsError;
+
+    fn try_from(value: u16) -> Result<Self> {
+        match value {
+            0 => Ok(Self::File),
+            1 => Ok(Self::Directory),
+            2 => Ok(Self::SymLink),
+            _ => Err(EfsError::Corrupted),
+        }
+    }
+}
+
+/// 磁盘上的索引节点
+///
+/// 文件的数据块通过三级索引进行定位:
+/// 1. direct直接指向数据块, 可以表示 27 * 512 = 13.5KiB 的数据
+/// 2. indirect1指向一个一级间接索引块, 块中的每个u32指向一个数据块, 可以额外表示 128 * 512 = 64KiB 的数据
+/// 3. indirect2指向一个二级间接索引块, 块中的每个u32指向一个一级间接索引块, 可以额外表示 128 * 64KiB = 8MiB 的数据
+///
+/// 带有INODE_FLAG_EXTENTS标志的inode则将这些字段解释为extent:
+/// 1. direct[0]为extent的数量, direct[1..27]直接保存前13个extent
+/// 2. indirect1指向一个extent块, 可以额外保存64个extent
+/// 3. indirect2指向一个二级索引块, 块中的每个u32指向一个extent块, 可以额外保存 128 * 64 个extent
+///
+/// 带有INODE_FLAG_INLINE标志的inode不占用任何数据块,
+/// 不超过116字节的文件内容按字节顺序直接保存在direct、indirect1和indirect2中
+///
+/// 带有INODE_FLAG_COMPRESSED标志的inode将内容划分为4KiB的块组, 第i个块组对应第8i到8i+7个块:
+/// 8个块都已分配时按原样保存; 都是空洞时内容全为0;
+/// 否则从第一个块开始连续保存 压缩后的长度(u32, 小端序) | 压缩后的数据
+///
+/// 带有INODE_FLAG_HASHED标志的目录大小总是块大小的整数倍, 每个块作为一个哈希桶,
+/// 目录项保存在从文件名的哈希值对应的块开始的DIR_INDEX_PROBE_BLOCKS个块之一中
+#[repr(C)]
+#[derive(Clone)]
+pub struct DiskInode {
+    // 文件的字节大小
+    pub size: u32,
+    // 硬链接计数, 即有多少个目录项指向这个inode
+    // 目录中的"."和".."不计入
+    pub nlink: u32,
+    // 直接索引
+    pub direct: [u32; INODE_DIRECT_COUNT],
+    // 一级间接索引
+    pub indirect1: u32,
+    // 二级间接索引
+    pub indirect2: u32,
+    // 索引节点类型, DiskInodeType的值
+    type_: u16,
+    // inode标志
+    // 旧的镜像中type_占用4个字节, 高位的两个字节总是为0, 因此读出的标志为0
+    flags: u16,
+}
+
+/// 磁盘上的inode元数据
+///
+/// 版本2的镜像中紧跟在每个DiskInode之后, 同样为128字节, 两者总是位于同一个块中;
+/// 时间为自UNIX纪元以来的秒数, 扩展属性块和数据块一样从数据位图中分配
+#[repr(C)]
+pub struct DiskInodeMeta {
+    // 权限位, 如0o644
+    pub mode: u32,
+    // 所有者的用户id
+    pub uid: u32,
+    // 所有者的组id
+    pub gid: u32,
+    // 最后访问时间
+    pub atime: u32,
+    // 最后修改内容的时间
+    pub mtime: u32,
+    // 最后修改inode的时间, 包括内容、权限、所有者以及硬链接计数
+    pub ctime: u32,
+    // 扩展属性块的块编号, 为0时表示没有扩展属性
+    pub xattr_block: u32,
+    // 保留给以后的版本使用
+    reserved: [u32; 25],
+}
+
+impl DiskInodeMeta {
+    /// 初始化新建inode的元数据, 所有者为0, 三个时间都设为now
+    pub fn initialize(&mut self, mode: u32, now: u32) {
+        self.mode = mode;
+        self.uid = 0;
+        self.gid = 0;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        self.xattr_block = 0;
+        self.reserved.iter_mut().for_each(|v| *v = 0);
+    }
+}
+
+/// 一段连续的数据块, start为0时表示len个块的空洞
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Extent {
+    // 起始块编号
+    start: u32,
+    // 连续的块数量
+    len: u32,
+}
+
+impl DiskInode {
+    /// 初始化一个索引节点
+    ///
+    /// 索引块会在需要的时候才进行分配, 因此这里都置为0
+    pub fn initialize(&mut self, type_: DiskInodeType, flags: u16) {
+        self.size = 0;
+        self.nlink = 1;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        self.type_ = type_ as u16;
+        self.flags = flags;
+    }
+
+    /// 数据块是否通过extent进行定位
+    pub fn is_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+
+    /// 文件内容是否直接保存在DiskInode中
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+
+    /// 文件内容是否按块组压缩保存
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESSED != 0
+    }
+
+    /// 目录项是否按照文件名的哈希值排列
+    pub fn is_hashed(&self) -> bool {
+        self.flags & INODE_FLAG_HASHED != 0
+    }
+
+    /// 标记目录项按照文件名的哈希值排列, 调用者需要同时按照哈希目录的格式重写目录的内容
+    pub fn set_hashed(&mut self) {
+        self.flags |= INODE_FLAG_HASHED;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.type_ == DiskInodeType::Directory as u16
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.type_ == DiskInodeType::File as u16
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::SymLink as u16
+    }
+
+    /// 索引节点的类型, 磁盘上保存的值不合法时返回EfsError::Corrupted
+    pub fn type_(&self) -> Result<DiskInodeType> {
+        DiskInodeType::try_from(self.type_)
+    }
+
+    /// 获取文件内容的第inner_id个数据块在磁盘上的块编号
+    ///
+    /// 返回0表示这个块是一个空洞, 还没有分配数据块, 内容保存在DiskInode中时总是返回0
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> Result<u32> {
+        if self.is_inline() {
+            return Ok(0);
+        }
+        if self.is_extents() {
+            return self.get_extent_block_id(inner_id, block_device);
+        }
+        let inner_id = inner_id as usize;
+        if inner_id < DIRECT_BOUND {
+            Ok(self.direct[inner_id])
+        } else if inner_id < INDIRECT1_BOUND {
+            Self::read_index(self.indirect1, inner_id - DIRECT_BOUND, block_device)
+        } else if inner_id < INDIRECT2_BOUND {
+            let last = inner_id - INDIRECT1_BOUND;
+            // 先找到二级索引块中对应的一级索引块
+            let indirect1 =
+                Self::read_index(self.indirect2, last / INODE_INDIRECT1_COUNT, block_device)?;
+            Self::read_index(indirect1, last % INODE_INDIRECT1_COUNT, block_device)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// 设置文件内容的第inner_id个数据块, 缺少的索引块通过alloc分配
+    ///
+    /// 超出块索引能够表示的范围时返回EfsError::FileTooLarge
+    fn set_block_id(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        let inner_id = inner_id as usize;
+        if inner_id >= INDIRECT2_BOUND {
+            return Err(EfsError::FileTooLarge);
+        }
+        if inner_id < DIRECT_BOUND {
+            self.direct[inner_id] = block_id;
+        } else if inner_id < INDIRECT1_BOUND {
+            if self.indirect1 == 0 {
+                self.indirect1 = Self::new_index_block(alloc, block_device)?;
+            }
+            Self::write_index(
+                self.indirect1,
+                inner_id - DIRECT_BOUND,
+                block_id,
+                block_device,
+            )?;
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            if self.indirect2 == 0 {
+                self.indirect2 = Self::new_index_block(alloc, block_device)?;
+            }
+            let mut indirect1 =
+                Self::read_index(self.indirect2, last / INODE_INDIRECT1_COUNT, block_device)?;
+            if indirect1 == 0 {
+                indirect1 = Self::new_index_block(alloc, block_device)?;
+                Self::write_index(
+                    self.indirect2,
+                    last / INODE_INDIRECT1_COUNT,
+                    indirect1,
+                    block_device,
+                )?;
+            }
+            Self::write_index(
+                indirect1,
+                last % INODE_INDIRECT1_COUNT,
+                block_id,
+                block_device,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// 读取索引块中的第idx项, 索引块为0(不存在)时返回0
+    fn read_index(
+        index_block: u32,
+        idx: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        if index_block == 0 {
+            return Ok(0);
+        }
+        get_block_cache(index_block as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| indirect_block[idx])
+    }
+
+    /// 写入索引块中的第idx项
+    fn write_index(
+        index_block: u32,
+        idx: usize,
+        value: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        get_block_cache(index_block as usize, Arc::clone(block_device))?
+            .lock()
+            .modify(0, |indirect_block: &mut IndirectBlock| {
+                indirect_block[idx] = value;
+            })
+    }
+
+    /// 索引块中的所有项是否都为0
+    fn is_empty_index(index_block: u32, block_device: &Arc<dyn BlockDevice>) -> Result<bool> {
+        get_block_cache(index_block as usize, Arc::clone(block_device))?
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| {
+                indirect_block.iter().all(|&entry| entry == 0)
+            })
+    }
+
+    /// 通过alloc分配一个索引块并将它清零
+    fn new_index_block(
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        let block_id = alloc()?;
+        get_block_cache(block_id as usize, Arc::clone(block_device))?
+            .lock()
+            .modify(0, |indirect_block: &mut IndirectBlock| {
+                indirect_block.iter_mut().for_each(|entry| *entry = 0);
+            })?;
+        Ok(block_id)
+    }
+
+    /// 文件内容占用的数据块数量, 包括空洞
+    ///
+    /// 压缩文件按整个块组计算, 最后一个块组同样占用CHUNK_BLOCKS个块编号
+    pub fn data_blocks(&self) -> u32 {
+        let data_blocks = Self::_data_blocks(self.size);
+        if self.is_compressed() {
+            data_blocks.div_ceil(CHUNK_BLOCKS) * CHUNK_BLOCKS
+        } else {
+            data_blocks
+        }
+    }
+
+    /// 文件最多可以容纳的字节数
+    pub fn max_size(&self) -> usize {
+        let max_size = if self.is_extents() {
+            u32::MAX as usize
+        } else {
+            INDIRECT2_BOUND * BLOCK_SZ
+        };
+        if self.is_compressed() {
+            max_size / COMPRESSED_CHUNK_SZ * COMPRESSED_CHUNK_SZ
+        } else {
+            max_size
+        }
+    }
+
+    fn _data_blocks(size: u32) -> u32 {
+        size.div_ceil(BLOCK_SZ as u32)
+    }
+
+    /// 按字节顺序读出direct、indirect1和indirect2所占的空间
+    fn inline_bytes(&self) -> [u8; INLINE_DATA_LIMIT] {
+        let mut bytes = [0u8; INLINE_DATA_LIMIT];
+        let words = self.direct.iter().chain([&self.indirect1, &self.indirect2]);
+        for (chunk, word) in bytes.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// 按字节顺序写入direct、indirect1和indirect2所占的空间
+    fn set_inline_bytes(&mut self, bytes: &[u8; INLINE_DATA_LIMIT]) {
+        let words = self
+            .direct
+            .iter_mut()
+            .chain([&mut self.indirect1, &mut self.indirect2]);
+        for (chunk, word) in bytes.chunks(4).zip(words) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+    }
+
+    /// 保存在DiskInode中的文件内容的字节数, 超过INLINE_DATA_LIMIT说明inode已经损坏
+    fn inline_size(&self) -> Result<usize> {
+        let size = self.size as usize;
+        if size > INLINE_DATA_LIMIT {
+            return Err(EfsError::Corrupted);
+        }
+        Ok(size)
+    }
+
+    /// 取出直接保存在DiskInode中的文件内容, 之后inode改为通过数据块保存内容
+    ///
+    /// 文件大小保持不变, 调用者需要为返回的内容分配数据块并重新写入;
+    /// 内容没有保存在DiskInode中时返回EfsError::InvalidArgument
+    pub fn take_inline(&mut self) -> Result<Vec<u8>> {
+        if !self.is_inline() {
+            return Err(EfsError::InvalidArgument);
+        }
+        let data = self.inline_bytes()[..self.inline_size()?].to_vec();
+        self.set_inline_bytes(&[0u8; INLINE_DATA_LIMIT]);
+        self.flags &= !INODE_FLAG_INLINE;
+        Ok(data)
+    }
+
+    /// 将data作为文件的全部内容直接保存在DiskInode中
+    ///
+    /// 调用者需要先回收inode占用的所有块, data超过INLINE_DATA_LIMIT时返回EfsError::InvalidArgument
+    pub fn store_inline(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > INLINE_DATA_LIMIT {
+            return Err(EfsError::InvalidArgument);
+        }
+        let mut bytes = [0u8; INLINE_DATA_LIMIT];
+        bytes[..data.len()].copy_from_slice(data);
+        self.set_inline_bytes(&bytes);
+        self.size = data.len() as u32;
+        self.flags |= INODE_FLAG_INLINE;
+        Ok(())
+    }
+
+    /// 统计[start_block, end_block)范围内还没有分配数据块的空洞数量
+    pub fn count_holes(
+        &self,
+        start_block: u32,
+        end_block: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        if self.is_inline() {
+            // 内容保存在DiskInode中时, 所有的块都需要在移出内容之后重新分配
+            return Ok(end_block.saturating_sub(start_block));
+        }
+        if self.is_extents() {
+            let extents = self.load_extents(block_device)?;
+            return Ok(Self::extents_range(&extents, start_block, end_block)
+                .iter()
+                .filter(|&&block_id| block_id == 0)
+                .count() as u32);
+        }
+        let mut holes = 0;
+        for inner_id in start_block..end_block {
+            if self.get_block_id(inner_id, block_device)? == 0 {
+                holes += 1;
+            }
+        }
+        Ok(holes)
+    }
+
+    /// 向[start_block, end_block)范围内填充holes个新的数据块时, 最多还需要分配的索引块或extent块数量
+    ///
+    /// 索引格式下为范围涉及的、还没有分配的一级索引块和二级索引块;
+    /// extent格式下假设每个新的数据块都单独成为一个extent, 计算extent块数量的增加;
+    /// 内容保存在DiskInode中时按照还没有任何索引块计算
+    pub fn index_blocks_needed(
+        &self,
+        start_block: u32,
+        end_block: u32,
+        holes: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        if holes == 0 || start_block >= end_block {
+            return Ok(0);
+        }
+        let (indirect1, indirect2) = if self.is_inline() {
+            (0, 0)
+        } else {
+            (self.indirect1, self.indirect2)
+        };
+        let mut needed = 0;
+        if self.is_extents() {
+            let old_count = if self.is_inline() {
+                0
+            } else {
+                self.extent_count()
+            };
+            let new_count = (old_count + holes as usize).min(EXTENT2_BOUND);
+            if new_count > INODE_INLINE_EXTENTS && indirect1 == 0 {
+                needed += 1;
+            }
+            let new_blocks = Self::level2_extent_blocks(new_count);
+            if new_blocks > 0 && indirect2 == 0 {
+                needed += 1;
+            }
+            return Ok(needed + (new_blocks - Self::level2_extent_blocks(old_count)) as u32);
+        }
+        let (start, end) = (start_block as usize, end_block as usize);
+        if start < INDIRECT1_BOUND && end > DIRECT_BOUND && indirect1 == 0 {
+            needed += 1;
+        }
+        if end > INDIRECT1_BOUND {
+            if indirect2 == 0 {
+                needed += 1;
+            }
+            let first = start.max(INDIRECT1_BOUND) - INDIRECT1_BOUND;
+            let last = end.min(INDIRECT2_BOUND) - 1 - INDIRECT1_BOUND;
+            for idx in first / INODE_INDIRECT1_COUNT..=last / INODE_INDIRECT1_COUNT {
+                if Self::read_index(indirect2, idx, block_device)? == 0 {
+                    needed += 1;
+                }
+            }
+        }
+        Ok(needed)
+    }
+
+    /// 依次使用new_blocks填充[start_block, end_block)范围内的空洞
+    ///
+    /// new_blocks为调用者预先通过位图分配好的数据块, 数量必须等于count_holes的返回值,
+    /// 否则返回EfsError::InvalidArgument;
+    /// 缺少的索引块或extent块通过alloc分配, 返回不再需要的extent块, 由调用者进行回收;
+    /// 内容保存在DiskInode中时没有空洞可以填充, 同样返回EfsError::InvalidArgument
+    pub fn fill_holes(
+        &mut self,
+        start_block: u32,
+        end_block: u32,
+        new_blocks: Vec<u32>,
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        if self.is_inline() || start_block > end_block || end_block > self.data_blocks() {
+            return Err(EfsError::InvalidArgument);
+        }
+        let mut new_blocks = new_blocks.into_iter();
+        if self.is_extents() {
+            let extents = self.load_extents(block_device)?;
+            let range = Self::extents_range(&extents, start_block, end_block)
+                .into_iter()
+                .map(|block_id| {
+                    if block_id == 0 {
+                        new_blocks.next().ok_or(EfsError::InvalidArgument)
+                    } else {
+                        Ok(block_id)
+                    }
+                })
+                .collect::<Result<Vec<u32>>>()?;
+            let extents = Self::replace_extents(&extents, start_block, &range);
+            return self.store_extents(&extents, alloc, block_device);
+        }
+        for inner_id in start_block..end_block {
+            if self.get_block_id(inner_id, block_device)? == 0 {
+                let block_id = new_blocks.next().ok_or(EfsError::InvalidArgument)?;
+                self.set_block_id(inner_id, block_id, alloc, block_device)?;
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// 回收[start_block, end_block)范围内的数据块, 让这个范围成为空洞
+    ///
+    /// 拆分extent时可能需要通过alloc分配新的extent块;
+    /// 返回被回收的数据块以及不再需要的索引块或extent块, 由调用者通过位图进行回收;
+    /// 内容保存在DiskInode中时只将范围内的字节清零
+    pub fn punch_hole(
+        &mut self,
+        start_block: u32,
+        end_block: u32,
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        let end_block = end_block.min(self.data_blocks());
+        if start_block >= end_block {
+            return Ok(Vec::new());
+        }
+        if self.is_inline() {
+            let mut bytes = self.inline_bytes();
+            let start = (start_block as usize * BLOCK_SZ).min(INLINE_DATA_LIMIT);
+            let end = (end_block as usize * BLOCK_SZ).min(INLINE_DATA_LIMIT);
+            bytes[start..end].iter_mut().for_each(|byte| *byte = 0);
+            self.set_inline_bytes(&bytes);
+            return Ok(Vec::new());
+        }
+        if self.is_extents() {
+            let extents = self.load_extents(block_device)?;
+            let mut freed: Vec<u32> = Self::extents_range(&extents, start_block, end_block)
+                .into_iter()
+                .filter(|&block_id| block_id != 0)
+                .collect();
+            let holes = vec![0u32; (end_block - start_block) as usize];
+            let extents = Self::replace_extents(&extents, start_block, &holes);
+            freed.extend(self.store_extents(&extents, alloc, block_device)?);
+            return Ok(freed);
+        }
+        let mut freed: Vec<u32> = Vec::new();
+        for inner_id in start_block..end_block {
+            let block_id = self.get_block_id(inner_id, block_device)?;
+            if block_id != 0 {
+                freed.push(block_id);
+                self.set_block_id(inner_id, 0, alloc, block_device)?;
+            }
+        }
+        // 回收已经全部成为空洞的索引块
+        if self.indirect1 != 0 && Self::is_empty_index(self.indirect1, block_device)? {
+            freed.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        if self.indirect2 != 0 {
+            for idx in 0..INODE_INDIRECT1_COUNT {
+                let indirect1 = Self::read_index(self.indirect2, idx, block_device)?;
+                if indirect1 != 0 && Self::is_empty_index(indirect1, block_device)? {
+                    freed.push(indirect1);
+                    Self::write_index(self.indirect2, idx, 0, block_device)?;
+                }
+            }
+            if Self::is_empty_index(self.indirect2, block_device)? {
+                freed.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        Ok(freed)
+    }
+
+    /// 获取[start_block, end_block)范围内的数据块编号, 空洞为0
+    pub fn get_block_ids(
+        &self,
+        start_block: u32,
+        end_block: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        if self.is_extents() && !self.is_inline() {
+            let extents = self.load_extents(block_device)?;
+            return Ok(Self::extents_range(&extents, start_block, end_block));
+        }
+        (start_block..end_block)
+            .map(|inner_id| self.get_block_id(inner_id, block_device))
+            .collect()
+    }
+
+    /// 将从start_block开始的数据块依次替换为blocks
+    ///
+    /// 缺少的索引块或extent块通过alloc分配, 返回不再需要的extent块, 由调用者进行回收;
+    /// 被替换掉的数据块同样由调用者负责回收
+    pub fn set_block_ids(
+        &mut self,
+        start_block: u32,
+        blocks: &[u32],
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        if self.is_inline() {
+            return Err(EfsError::InvalidArgument);
+        }
+        if self.is_extents() {
+            let extents = self.load_extents(block_device)?;
+            let extents = Self::replace_extents(&extents, start_block, blocks);
+            return self.store_extents(&extents, alloc, block_device);
+        }
+        for (inner_id, &block_id) in (start_block..).zip(blocks) {
+            self.set_block_id(inner_id, block_id, alloc, block_device)?;
+        }
+        Ok(Vec::new())
+    }
+
+    /// 让索引块或extent块只属于这个inode, 在原地修改它们之前调用
+    ///
+    /// cow返回可以直接修改的块编号: 块没有被快照共享时就是原来的块, 否则为复制出的新块
+    pub fn unshare_index_blocks(
+        &mut self,
+        cow: &mut dyn FnMut(u32) -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        if self.is_inline() {
+            return Ok(());
+        }
+        if self.indirect1 != 0 {
+            self.indirect1 = cow(self.indirect1)?;
+        }
+        if self.indirect2 != 0 {
+            self.indirect2 = cow(self.indirect2)?;
+            for idx in 0..INODE_INDIRECT1_COUNT {
+                let block_id = Self::read_index(self.indirect2, idx, block_device)?;
+                if block_id == 0 {
+                    continue;
+                }
+                let new_block_id = cow(block_id)?;
+                if new_block_id != block_id {
+                    Self::write_index(self.indirect2, idx, new_block_id, block_device)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 获取文件占用的所有块编号(包括索引块), 不会修改文件
+    ///
+    /// 空洞以及直接保存在DiskInode中的内容不占用数据块, 不会出现在结果中
+    pub fn blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>> {
+        if self.is_inline() {
+            return Ok(Vec::new());
+        }
+        if self.is_extents() {
+            return self.extent_blocks(block_device);
+        }
+        let data_blocks = self.data_blocks() as usize;
+        let mut v: Vec<u32> = self
+            .direct
+            .iter()
+            .take(data_blocks)
+            .copied()
+            .filter(|&block_id| block_id != 0)
+            .collect();
+        let push_entries = |v: &mut Vec<u32>, index_block: u32, count: usize| {
+            get_block_cache(index_block as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| {
+                    v.extend(
+                        indirect_block[..count]
+                            .iter()
+                            .filter(|&&block_id| block_id != 0),
+                    );
+                })
+        };
+        if self.indirect1 != 0 {
+            v.push(self.indirect1);
+            let count = data_blocks
+                .saturating_sub(DIRECT_BOUND)
+                .min(INODE_INDIRECT1_COUNT);
+            push_entries(&mut v, self.indirect1, count)?;
+        }
+        if self.indirect2 != 0 {
+            v.push(self.indirect2);
+            let last = data_blocks.saturating_sub(INDIRECT1_BOUND);
+            for idx in 0..INODE_INDIRECT1_COUNT {
+                let indirect1 = Self::read_index(self.indirect2, idx, block_device)?;
+                if indirect1 == 0 {
+                    continue;
+                }
+                v.push(indirect1);
+                let count = last
+                    .saturating_sub(idx * INODE_INDIRECT1_COUNT)
+                    .min(INODE_INDIRECT1_COUNT);
+                push_entries(&mut v, indirect1, count)?;
+            }
+        }
+        Ok(v)
+    }
+
+    /// 清空文件内容
+    ///
+    /// 返回文件占用的所有块编号(包括索引块), 由调用者通过位图进行回收
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>> {
+        let v = self.blocks(block_device)?;
+        self.size = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        Ok(v)
+    }
+
+    /// 容纳count个extent时二级索引块中需要的extent块数量
+    fn level2_extent_blocks(count: usize) -> usize {
+        count
+            .saturating_sub(EXTENT1_BOUND)
+            .div_ceil(EXTENTS_PER_BLOCK)
+    }
+
+    /// 将块编号按照是否连续划分为若干个extent, 连续的0划分为一个空洞
+    fn runs(blocks: &[u32]) -> Vec<Extent> {
+        let mut runs: Vec<Extent> = Vec::new();
+        for &block_id in blocks {
+            match runs.last_mut() {
+                Some(run) if run.can_append(block_id) => run.len += 1,
+                _ => runs.push(Extent {
+                    start: block_id,
+                    len: 1,
+                }),
+            }
+        }
+        runs
+    }
+
+    /// extent的数量
+    fn extent_count(&self) -> usize {
+        (self.direct[0] as usize).min(EXTENT2_BOUND)
+    }
+
+    /// 读取第idx个extent
+    fn get_extent(&self, idx: usize, block_device: &Arc<dyn BlockDevice>) -> Result<Extent> {
+        if idx < INODE_INLINE_EXTENTS {
+            Ok(Extent {
+                start: self.direct[1 + 2 * idx],
+                len: self.direct[2 + 2 * idx],
+            })
+        } else {
+            let (block_id, offset) = self.extent_pos(idx, block_device)?;
+            get_block_cache(block_id as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |extent_block: &ExtentBlock| extent_block[offset])
+        }
+    }
+
+    /// 写入第idx个extent, 它所在的extent块必须已经分配
+    fn set_extent(
+        &mut self,
+        idx: usize,
+        extent: Extent,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<()> {
+        if idx < INODE_INLINE_EXTENTS {
+            self.direct[1 + 2 * idx] = extent.start;
+            self.direct[2 + 2 * idx] = extent.len;
+            Ok(())
+        } else {
+            let (block_id, offset) = self.extent_pos(idx, block_device)?;
+            get_block_cache(block_id as usize, Arc::clone(block_device))?
+                .lock()
+                .modify(0, |extent_block: &mut ExtentBlock| {
+                    extent_block[offset] = extent;
+                })
+        }
+    }
+
+    /// 不直接保存在inode中的第idx个extent所在的(extent块编号, 块内下标)
+    fn extent_pos(&self, idx: usize, block_device: &Arc<dyn BlockDevice>) -> Result<(u32, usize)> {
+        if idx < EXTENT1_BOUND {
+            Ok((self.indirect1, idx - INODE_INLINE_EXTENTS))
+        } else {
+            let last = idx - EXTENT1_BOUND;
+            let block_id = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect2[last / EXTENTS_PER_BLOCK]
+                })?;
+            Ok((block_id, last % EXTENTS_PER_BLOCK))
+        }
+    }
+
+    /// 依次查找extent, 获取文件内容的第inner_id个数据块在磁盘上的块编号
+    fn get_extent_block_id(
+        &self,
+        mut inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32> {
+        for idx in 0..self.extent_count() {
+            let extent = self.get_extent(idx, block_device)?;
+            if inner_id < extent.len {
+                return Ok(extent.block_at(inner_id));
+            }
+            inner_id -= extent.len;
+        }
+        // 最后一个extent之后的部分都是空洞
+        Ok(0)
+    }
+
+    /// 读出所有的extent
+    fn load_extents(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<Extent>> {
+        (0..self.extent_count())
+            .map(|idx| self.get_extent(idx, block_device))
+            .collect()
+    }
+
+    /// 将extents中[start_block, end_block)范围展开为块编号, 空洞为0
+    fn extents_range(extents: &[Extent], start_block: u32, end_block: u32) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::with_capacity((end_block - start_block) as usize);
+        let mut pos = 0u32;
+        for extent in extents {
+            let extent_end = pos.saturating_add(extent.len);
+            for inner_id in pos.max(start_block)..extent_end.min(end_block) {
+                v.push(extent.block_at(inner_id - pos));
+            }
+            pos = extent_end;
+            if pos >= end_block {
+                break;
+            }
+        }
+        v.resize((end_block - start_block) as usize, 0);
+        v
+    }
+
+    /// 将extents中从start_block开始的部分替换为blocks, 返回合并之后的extent
+    ///
+    /// 末尾的空洞不需要记录, 最后一个extent之后的部分总是被看作空洞
+    fn replace_extents(extents: &[Extent], start_block: u32, blocks: &[u32]) -> Vec<Extent> {
+        let end_block = start_block + blocks.len() as u32;
+        let mut v: Vec<Extent> = Vec::new();
+        let push = |v: &mut Vec<Extent>, extent: Extent| {
+            if extent.len == 0 {
+                return;
+            }
+            match v.last_mut() {
+                Some(last) if last.can_append(extent.start) => last.len += extent.len,
+                _ => v.push(extent),
+            }
+        };
+        // start_block之前的部分
+        let mut pos = 0u32;
+        for extent in extents {
+            if pos < start_block {
+                let len = extent.len.min(start_block - pos);
+                push(&mut v, extent.slice(0, len));
+            }
+            pos = pos.saturating_add(extent.len);
+        }
+        if pos < start_block {
+            push(&mut v, Extent::hole(start_block - pos));
+        }
+        for run in Self::runs(blocks) {
+            push(&mut v, run);
+        }
+        // end_block之后的部分
+        pos = 0;
+        for extent in extents {
+            let extent_end = pos.saturating_add(extent.len);
+            if extent_end > end_block {
+                let offset = end_block.saturating_sub(pos);
+                push(&mut v, extent.slice(offset, extent.len));
+            }
+            pos = extent_end;
+        }
+        while matches!(v.last(), Some(extent) if extent.start == 0) {
+            v.pop();
+        }
+        v
+    }
+
+    /// 将extent写回inode, 并根据extent的数量调整extent块
+    ///
+    /// 新需要的extent块通过alloc分配, 返回不再需要的extent块;
+    /// extent的数量超出extent块能够容纳的范围时返回EfsError::FileTooLarge
+    fn store_extents(
+        &mut self,
+        extents: &[Extent],
+        alloc: &mut dyn FnMut() -> Result<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>> {
+        if extents.len() > EXTENT2_BOUND {
+            return Err(EfsError::FileTooLarge);
+        }
+        let old_count = self.extent_count();
+        let new_count = extents.len();
+        let mut freed: Vec<u32> = Vec::new();
+        if new_count > INODE_INLINE_EXTENTS {
+            if self.indirect1 == 0 {
+                self.indirect1 = alloc()?;
+            }
+        } else if self.indirect1 != 0 {
+            freed.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        let old_blocks = Self::level2_extent_blocks(old_count);
+        let new_blocks = Self::level2_extent_blocks(new_count);
+        if new_blocks > 0 && self.indirect2 == 0 {
+            self.indirect2 = Self::new_index_block(alloc, block_device)?;
+        }
+        if self.indirect2 != 0 {
+            for idx in new_blocks..old_blocks {
+                freed.push(Self::read_index(self.indirect2, idx, block_device)?);
+                Self::write_index(self.indirect2, idx, 0, block_device)?;
+            }
+            for idx in old_blocks..new_blocks {
+                let extent_block = alloc()?;
+                Self::write_index(self.indirect2, idx, extent_block, block_device)?;
+            }
+            if new_blocks == 0 {
+                freed.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        // 只写入发生变化的extent, 避免一次修改让所有的extent块都成为脏块
+        for (idx, extent) in extents.iter().enumerate() {
+            if idx >= old_count || self.get_extent(idx, block_device)? != *extent {
+                self.set_extent(idx, *extent, block_device)?;
+            }
+        }
+        for idx in new_count..old_count.min(INODE_INLINE_EXTENTS) {
+            self.set_extent(idx, Extent::default(), block_device)?;
+        }
+        self.direct[0] = new_count as u32;
+        Ok(freed)
+    }
+
+    /// 获取extent的文件占用的所有块编号, 依次为数据块和extent块
+    ///
+    /// 只展开文件大小以内的数据块, 避免损坏的extent产生过多的块编号
+    fn extent_blocks(&self, block_device: &Arc<dyn BlockDevice>) -> Result<Vec<u32>> {
+        let data_blocks = self.data_blocks();
+        let count = self.extent_count();
+        let mut v: Vec<u32> = Vec::new();
+        let mut pos = 0u32;
+        for idx in 0..count {
+            let extent = self.get_extent(idx, block_device)?;
+            if extent.start != 0 && pos < data_blocks {
+                let len = extent.len.min(data_blocks - pos);
+                v.extend((0..len).map(|i| extent.block_at(i)));
+            }
+            pos = pos.saturating_add(extent.len);
+        }
+        if self.indirect1 != 0 {
+            v.push(self.indirect1);
+        }
+        if self.indirect2 != 0 {
+            v.push(self.indirect2);
+            let extent_block_count = Self::level2_extent_blocks(count);
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))?
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    v.extend_from_slice(&indirect2[..extent_block_count]);
+                })?;
+        }
+        Ok(v)
+    }
+
+    /// 从文件的offset字节处开始读取数据到buf中
+    ///
+    /// 返回实际读取的字节数, 读到文件末尾时会小于buf的长度
+    pub fn read_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize> {
+        let mut start = offset;
+        let end = (offset + buf.len()).min(self.size as usize);
+        if start >= end {
+            return Ok(0);
+        }
+        if self.is_inline() {
+            self.inline_size()?;
+            buf[..end - start].copy_from_slice(&self.inline_bytes()[start..end]);
+            return Ok(end - start);
+        }
+        let mut start_block = start / BLOCK_SZ;
+        let mut read_size = 0usize;
+        loop {
+            // 当前块的结束位置
+            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            end_current_block = end_current_block.min(end);
+            let block_read_size = end_current_block - start;
+            let dst = &mut buf[read_size..read_size + block_read_size];
+            let block_id = self.get_block_id(start_block as u32, block_device)?;
+            if block_id == 0 {
+                // 空洞读出的都是0
+                dst.iter_mut().for_each(|byte| *byte = 0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))?
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                        dst.copy_from_slice(src);
+                    })?;
+            }
+            read_size += block_read_size;
+            if end_current_block == end {
+                break;
+            }
+            start_block += 1;
+            start = end_current_block;
+        }
+        Ok(read_size)
+    }
+
+    /// 将buf中的数据写入到文件的offset字节处
+    ///
+    /// 调用者需要保证文件已经扩容到足够的大小, 并且范围内的空洞已经通过fill_holes分配了数据块;
+    /// 只写入文件大小以内的部分, 返回实际写入的字节数, 范围超出文件末尾时会小于buf的长度;
+    /// offset超出文件大小或者遇到空洞时返回EfsError::InvalidArgument
+    pub fn write_at(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize> {
+        let mut start = offset;
+        let end = (offset + buf.len()).min(self.size as usize);
+        if start > end {
+            return Err(EfsError::InvalidArgument);
+        }
+        if start == end {
+            return Ok(0);
+        }
+        if self.is_inline() {
+            self.inline_size()?;
+            let mut bytes = self.inline_bytes();
+            bytes[start..end].copy_from_slice(&buf[..end - start]);
+            self.set_inline_bytes(&bytes);
+            return Ok(end - start);
+        }
+        let mut start_block = start / BLOCK_SZ;
+        let mut write_size = 0usize;
+        loop {
+            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            end_current_block = end_current_block.min(end);
+            let block_write_size = end_current_block - start;
+            let block_id = self.get_block_id(start_block as u32, block_device)?;
+            if block_id == 0 {
+                return Err(EfsError::InvalidArgument);
+            }
+            get_block_cache(block_id as usize, Arc::clone(block_device))?
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    let src = &buf[write_size..write_size + block_write_size];
+                    let dst =
+                        &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                    dst.copy_from_slice(src);
+                })?;
+            write_size += block_write_size;
+            if end_current_block == end {
+                break;
+            }
+            start_block += 1;
+            start = end_current_block;
+        }
+        Ok(write_size)
+    }
+}
+
+impl Extent {
+    /// 长度为len的空洞
+    fn hole(len: u32) -> Self {
+        Self { start: 0, len }
     }
-}
\ No newline at end of file
+
+    /// extent中第offset个块的块编号, 空洞返回0
+    fn block_at(&self, offset: u32) -> u32 {
+        if self.start == 0 {
+            0
+        } else {
+            self.start.wrapping_add(offset)
+        }
+    }
+
+    /// extent中[from, to)这一部分
+    fn slice(&self, from: u32, to: u32) -> Self {
+        Self {
+            start: self.block_at(from),
+            len: to - from,
+        }
+    }
+
+    /// block_id能否追加到extent的末尾, 空洞之后只能追加空洞
+    fn can_append(&self, block_id: u32) -> bool {
+        if self.start == 0 {
+            block_id == 0
+        } else {
+            block_id != 0 && self.start.wrapping_add(self.len) == block_id
+        }
+    }
+}
+
+/// 目录项
+///
+/// 目录的内容就是一个个连续排列的目录项, 每个目录项为32字节
+#[repr(C)]
+pub struct DirEntry {
+    // 文件名, 以'\0'结尾
+    name: [u8; NAME_LENGTH_LIMIT + 1],
+    // 文件对应的inode编号
+    inode_number: u32,
+}
+
+impl DirEntry {
+    pub fn empty() -> Self {
+        Self {
+            name: [0u8; NAME_LENGTH_LIMIT + 1],
+            inode_number: 0,
+        }
+    }
+
+    /// 文件名超过NAME_LENGTH_LIMIT时返回EfsError::NameTooLong
+    pub fn new(name: &str, inode_number: u32) -> Result<Self> {
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(EfsError::NameTooLong);
+        }
+        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self {
+            name: bytes,
+            inode_number,
+        })
+    }
+
+    /// 将目录项作为字节数组, 用于通过DiskInode::write_at写入
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
+    }
+
+    /// 将目录项作为可变字节数组, 用于通过DiskInode::read_at读取
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
+    }
+
+    /// 文件名, 磁盘上的文件名没有结尾的'\0'或者不是合法的UTF-8时只取合法的前缀
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.name.len());
+        match core::str::from_utf8(&self.name[..len]) {
+            Ok(name) => name,
+            Err(err) => core::str::from_utf8(&self.name[..err.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        self.inode_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec::Vec};
+    use spin::Mutex;
+
+    use super::*;
+    use crate::{efs::EasyFileSystem, test_util::create_fs};
+
+    fn new_inode(flags: u16) -> DiskInode {
+        DiskInode {
+            size: 0,
+            nlink: 1,
+            direct: [0; INODE_DIRECT_COUNT],
+            indirect1: 0,
+            indirect2: 0,
+            type_: DiskInodeType::File as u16,
+            flags,
+        }
+    }
+
+    /// 测试用的块分配器, 不经过数据位图, 依次分配文件系统数据区域中的块
+    ///
+    /// 索引块在块缓存中被修改但不会提交, 文件系统释放时随之丢弃
+    struct Scratch {
+        _efs: Arc<Mutex<EasyFileSystem>>,
+        block_device: Arc<dyn BlockDevice>,
+        // 最后一个分配出的块
+        last: u32,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            let (_, efs) = create_fs(30000, 0);
+            let (block_device, first) = {
+                let fs = efs.lock();
+                (Arc::clone(&fs.block_device), fs.get_data_block_id(0))
+            };
+            Self {
+                _efs: efs,
+                block_device,
+                last: first,
+            }
+        }
+
+        /// 分配count个连续的数据块
+        fn contiguous(&mut self, count: u32) -> Vec<u32> {
+            let start = self.last + 1;
+            self.last += count;
+            (start..start + count).collect()
+        }
+
+        /// 为[start_block, end_block)中的空洞依次分配互不相邻的数据块
+        ///
+        /// 返回(分配的数据块, 分配的索引块或extent块), 调用之前文件大小需要已经覆盖这个范围
+        fn fill(
+            &mut self,
+            disk_inode: &mut DiskInode,
+            start_block: u32,
+            end_block: u32,
+        ) -> (Vec<u32>, Vec<u32>) {
+            let holes = disk_inode
+                .count_holes(start_block, end_block, &self.block_device)
+                .unwrap();
+            let data: Vec<u32> = (0..holes)
+                .map(|_| {
+                    self.last += 2;
+                    self.last
+                })
+                .collect();
+            let mut index = Vec::new();
+            let last = &mut self.last;
+            let freed = disk_inode
+                .fill_holes(
+                    start_block,
+                    end_block,
+                    data.clone(),
+                    &mut || {
+                        *last += 1;
+                        index.push(*last);
+                        Ok(*last)
+                    },
+                    &self.block_device,
+                )
+                .unwrap();
+            assert!(freed.is_empty());
+            (data, index)
+        }
+    }
+
+    #[test]
+    fn invalid_inode_type_is_corrupted() {
+        let mut disk_inode = new_inode(0);
+        assert_eq!(disk_inode.type_(), Ok(DiskInodeType::File));
+        disk_inode.type_ = 7;
+        assert_eq!(disk_inode.type_(), Err(EfsError::Corrupted));
+        assert!(!disk_inode.is_dir() && !disk_inode.is_file() && !disk_inode.is_symlink());
+    }
+
+    #[test]
+    fn indexed_mapping_spans_indirect_levels_and_clear_size_frees_all() {
+        let mut scratch = Scratch::new();
+        let block_device = Arc::clone(&scratch.block_device);
+        let mut disk_inode = new_inode(0);
+        // 覆盖直接索引、一级索引, 以及二级索引下的3个一级索引块
+        let end_block = (INDIRECT1_BOUND + 2 * INODE_INDIRECT1_COUNT + 1) as u32;
+        disk_inode.size = end_block * BLOCK_SZ as u32;
+        let (data, index) = scratch.fill(&mut disk_inode, 0, end_block);
+        assert_eq!(index.len(), 2 + 3);
+        assert_eq!(disk_inode.indirect1, index[0]);
+        assert_eq!(disk_inode.indirect2, index[1]);
+        // 空洞按照顺序填充, 第i个块应该对应第i个分配出的数据块
+        for inner_id in [
+            0,
+            DIRECT_BOUND as u32 - 1,
+            DIRECT_BOUND as u32,
+            INDIRECT1_BOUND as u32 - 1,
+            INDIRECT1_BOUND as u32,
+            (INDIRECT1_BOUND + INODE_INDIRECT1_COUNT) as u32,
+            end_block - 1,
+        ] {
+            assert_eq!(
+                disk_inode.get_block_id(inner_id, &block_device),
+                Ok(data[inner_id as usize])
+            );
+        }
+        assert_eq!(
+            disk_inode.get_block_ids(0, end_block, &block_device),
+            Ok(data.clone())
+        );
+
+        let mut freed = disk_inode.clear_size(&block_device).unwrap();
+        freed.sort_unstable();
+        let mut expected: Vec<u32> = data.into_iter().chain(index).collect();
+        expected.sort_unstable();
+        assert_eq!(freed, expected);
+        assert_eq!(disk_inode.size, 0);
+        assert_eq!((disk_inode.indirect1, disk_inode.indirect2), (0, 0));
+        assert!(disk_inode.direct.iter().all(|&block_id| block_id == 0));
+        assert_eq!(disk_inode.blocks(&block_device), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn extents_merge_adjacent_runs_and_split_on_holes() {
+        let mut scratch = Scratch::new();
+        let block_device = Arc::clone(&scratch.block_device);
+        let mut disk_inode = new_inode(INODE_FLAG_EXTENTS);
+        disk_inode.size = 100 * BLOCK_SZ as u32;
+        let mut no_alloc = || Err(EfsError::NoSpace);
+        let blocks = scratch.contiguous(100);
+        disk_inode
+            .set_block_ids(0, &blocks[..50], &mut no_alloc, &block_device)
+            .unwrap();
+        // 和上一个extent相邻的块合并到同一个extent中
+        disk_inode
+            .set_block_ids(50, &blocks[50..], &mut no_alloc, &block_device)
+            .unwrap();
+        assert_eq!(disk_inode.extent_count(), 1);
+        assert_eq!(disk_inode.get_block_id(73, &block_device), Ok(blocks[73]));
+        assert_eq!(
+            disk_inode.get_block_ids(0, 100, &block_device),
+            Ok(blocks.clone())
+        );
+
+        // 在中间打洞之后拆分为数据、空洞、数据3个extent
+        let mut freed = disk_inode
+            .punch_hole(40, 60, &mut no_alloc, &block_device)
+            .unwrap();
+        freed.sort_unstable();
+        assert_eq!(freed, blocks[40..60]);
+        assert_eq!(disk_inode.extent_count(), 3);
+        assert_eq!(disk_inode.count_holes(0, 100, &block_device), Ok(20));
+        assert_eq!(disk_inode.get_block_id(45, &block_device), Ok(0));
+
+        // 用原来的块填充空洞之后重新合并为一个extent
+        disk_inode
+            .fill_holes(
+                40,
+                60,
+                blocks[40..60].to_vec(),
+                &mut no_alloc,
+                &block_device,
+            )
+            .unwrap();
+        assert_eq!(disk_inode.extent_count(), 1);
+        assert_eq!(disk_inode.get_block_ids(0, 100, &block_device), Ok(blocks));
+        assert_eq!((disk_inode.indirect1, disk_inode.indirect2), (0, 0));
+    }
+
+    #[test]
+    fn fragmented_extents_use_extent_blocks() {
+        let mut scratch = Scratch::new();
+        let block_device = Arc::clone(&scratch.block_device);
+        let mut disk_inode = new_inode(INODE_FLAG_EXTENTS);
+        // 每个数据块都不相邻, 需要一级和二级extent块
+        let end_block = (EXTENT1_BOUND + EXTENTS_PER_BLOCK + 1) as u32;
+        disk_inode.size = end_block * BLOCK_SZ as u32;
+        let (data, index) = scratch.fill(&mut disk_inode, 0, end_block);
+        // indirect1, indirect2, 以及二级索引下的2个extent块
+        assert_eq!(index.len(), 4);
+        assert_eq!(disk_inode.extent_count(), end_block as usize);
+        for inner_id in [
+            0,
+            INODE_INLINE_EXTENTS as u32,
+            EXTENT1_BOUND as u32,
+            end_block - 1,
+        ] {
+            assert_eq!(
+                disk_inode.get_block_id(inner_id, &block_device),
+                Ok(data[inner_id as usize])
+            );
+        }
+        assert_eq!(disk_inode.get_block_id(end_block, &block_device), Ok(0));
+
+        let mut freed = disk_inode.clear_size(&block_device).unwrap();
+        freed.sort_unstable();
+        let mut expected: Vec<u32> = data.into_iter().chain(index).collect();
+        expected.sort_unstable();
+        assert_eq!(freed, expected);
+    }
+
+    #[test]
+    fn index_blocks_needed_covers_allocation() {
+        for flags in [0, INODE_FLAG_EXTENTS] {
+            let mut scratch = Scratch::new();
+            let block_device = Arc::clone(&scratch.block_device);
+            let mut disk_inode = new_inode(flags);
+            for (start_block, end_block) in [(0, 10), (20, 300), (5000, 5100), (100, 6000)] {
+                disk_inode.size = disk_inode.size.max(end_block * BLOCK_SZ as u32);
+                let holes = disk_inode
+                    .count_holes(start_block, end_block, &block_device)
+                    .unwrap();
+                let needed = disk_inode
+                    .index_blocks_needed(start_block, end_block, holes, &block_device)
+                    .unwrap();
+                let (_, index) = scratch.fill(&mut disk_inode, start_block, end_block);
+                let allocated = index.len() as u32;
+                if disk_inode.is_extents() {
+                    assert!(allocated <= needed);
+                } else {
+                    assert_eq!(allocated, needed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_at_stops_at_size_and_rejects_holes() {
+        let mut scratch = Scratch::new();
+        let block_device = Arc::clone(&scratch.block_device);
+        let mut disk_inode = new_inode(0);
+        disk_inode.size = 4 * BLOCK_SZ as u32;
+        scratch.fill(&mut disk_inode, 0, 2);
+        disk_inode.size = 2 * BLOCK_SZ as u32;
+        let buf = [6u8; 3 * BLOCK_SZ];
+        assert_eq!(
+            disk_inode.write_at(0, &buf, &block_device),
+            Ok(2 * BLOCK_SZ)
+        );
+        assert_eq!(
+            disk_inode.write_at(3 * BLOCK_SZ, &buf, &block_device),
+            Err(EfsError::InvalidArgument)
+        );
+        // 扩大之后第3个块是空洞, 必须先分配数据块才能写入
+        disk_inode.size = 3 * BLOCK_SZ as u32;
+        assert_eq!(
+            disk_inode.write_at(2 * BLOCK_SZ, &buf, &block_device),
+            Err(EfsError::InvalidArgument)
+        );
+        let mut read = [0u8; 3 * BLOCK_SZ];
+        assert_eq!(
+            disk_inode.read_at(0, &mut read, &block_device),
+            Ok(3 * BLOCK_SZ)
+        );
+        assert!(read[..2 * BLOCK_SZ].iter().all(|&byte| byte == 6));
+        assert!(read[2 * BLOCK_SZ..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn punch_hole_frees_blocks_and_empty_index_blocks() {
+        let mut scratch = Scratch::new();
+        let block_device = Arc::clone(&scratch.block_device);
+        let mut disk_inode = new_inode(0);
+        disk_inode.size = 300 * BLOCK_SZ as u32;
+        scratch.fill(&mut disk_inode, 0, 300);
+        disk_inode
+            .write_at(0, &[8u8; 300 * BLOCK_SZ], &block_device)
+            .unwrap();
+        let indirect1 = disk_inode.indirect1;
+        let freed = disk_inode
+            .punch_hole(10, 290, &mut || Err(EfsError::NoSpace), &block_device)
+            .unwrap();
+        // 280个数据块, 全部成为空洞的一级索引块, 以及二级索引下第一个一级索引块
+        assert_eq!(freed.len(), 280 + 2);
+        assert!(freed.contains(&indirect1));
+        assert_eq!(disk_inode.indirect1, 0);
+        assert_ne!(disk_inode.indirect2, 0);
+        assert_eq!(disk_inode.count_holes(0, 300, &block_device), Ok(280));
+        assert_eq!(disk_inode.size, 300 * BLOCK_SZ as u32);
+        let mut read = [1u8; 300 * BLOCK_SZ];
+        disk_inode.read_at(0, &mut read, &block_device).unwrap();
+        assert!(read[..10 * BLOCK_SZ].iter().all(|&byte| byte == 8));
+        assert!(read[10 * BLOCK_SZ..290 * BLOCK_SZ]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert!(read[290 * BLOCK_SZ..].iter().all(|&byte| byte == 8));
+    }
+}
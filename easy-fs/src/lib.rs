@@ -1,19 +1,53 @@
 #![no_std]
-#![feature(linkage)]
-#![feature(panic_info_message)]
-#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
+mod bitmap;
 mod block_cache;
 mod block_dev;
+mod clock;
+mod crc32;
+mod dir_index;
+mod efs;
+mod error;
+mod fsck;
+mod journal;
 mod layout;
-mod bitmap;
+mod lz;
+mod snapshot;
+#[cfg(test)]
+mod test_util;
+mod vfs;
+mod xattr;
 
-pub use block_cache::get_block_cache;
+pub use bitmap::Bitmap;
+pub use block_cache::{
+    block_cache_discard_device, block_cache_enable_checksums, block_cache_enable_readahead,
+    block_cache_invalidate_device, block_cache_stats, get_block_cache, get_zeroed_block_cache,
+    set_block_cache_capacity, BlockCache, BlockCacheManager, BlockCacheStats, BLOCK_CACHE_MANAGER,
+};
+pub use block_dev::BlockDevice;
+pub use clock::set_clock;
+pub use dir_index::{DIR_INDEX_PROBE_BLOCKS, DIR_INDEX_THRESHOLD_BLOCKS};
+pub use efs::EasyFileSystem;
+pub use error::{EfsError, Result};
+pub use fsck::{fsck, FsckReport};
+pub use layout::{
+    DirEntry, DiskInode, DiskInodeMeta, DiskInodeType, SuperBlock, CHUNK_BLOCKS,
+    COMPRESSED_CHUNK_SZ, DIRENT_SZ, FEATURE_CHECKSUMS, FEATURE_COMPRESSION, FEATURE_DIR_INDEX,
+    FEATURE_EXTENTS, FEATURE_INLINE_DATA, FEATURE_SNAPSHOTS, INLINE_DATA_LIMIT,
+    INODE_FLAG_COMPRESSED, INODE_FLAG_EXTENTS, INODE_FLAG_HASHED, INODE_FLAG_INLINE, STATE_RECOUNT,
+};
+pub use snapshot::{SnapshotInfo, MAX_SNAPSHOTS, SNAPSHOT_NAME_LIMIT};
+pub use vfs::{Inode, Stat};
+pub use xattr::XATTR_NAME_LIMIT;
 
 /// 块的大小，和磁盘扇区大小一致,都是512字节
 pub const BLOCK_SZ: usize = 512;
 /// 文件系统合法性校验
 pub const EFS_MAGIX: u32 = 11;
+/// 当前的磁盘格式版本
+///
+/// 版本2在每个inode之后增加了权限、所有者以及时间戳, 仍然可以打开版本1的镜像
+pub const EFS_VERSION: u32 = 2;
 pub const BLOCK_BITS: usize = 4096;
@@ -0,0 +1,211 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{EfsError, Result};
+
+/// 最短的匹配长度, 更短的重复内容直接作为字面量保存
+const MIN_MATCH: usize = 4;
+/// 哈希表的位数
+const HASH_BITS: u32 = 12;
+/// 匹配可以向前引用的最大距离
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+/// 压缩数据
+///
+/// 输出由若干个序列组成, 每个序列为:
+/// 标记字节 | 字面量长度的扩展字节 | 字面量 | 匹配距离(u16, 小端序) | 匹配长度的扩展字节,
+/// 标记字节的高4位为字面量长度, 低4位为匹配长度减去MIN_MATCH, 为15时由之后的扩展字节累加,
+/// 扩展字节为255时还有下一个扩展字节; 最后一个序列只有字面量, 没有匹配
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(input.len() / 2);
+    // 以4字节的哈希查找之前出现过的位置, 保存的是位置加一, 0表示没有出现过
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let hash = hash(&input[pos..pos + MIN_MATCH]);
+        let candidate = table[hash];
+        table[hash] = pos + 1;
+        if candidate > 0 {
+            let candidate = candidate - 1;
+            if pos - candidate <= MAX_OFFSET
+                && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH]
+            {
+                let mut len = MIN_MATCH;
+                while pos + len < input.len() && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+                emit(&mut out, &input[anchor..pos], Some((pos - candidate, len)));
+                pos += len;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    emit(&mut out, &input[anchor..], None);
+    out
+}
+
+/// 将input解压到output中, 返回解压出的字节数
+///
+/// 数据不完整、引用了输出之前的位置或者解压结果超出output时返回EfsError::Corrupted
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize> {
+    let mut ip = 0;
+    let mut op = 0;
+    while ip < input.len() {
+        let token = input[ip];
+        ip += 1;
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += read_length(input, &mut ip)?;
+        }
+        let literals = input.get(ip..ip + literal_len).ok_or(EfsError::Corrupted)?;
+        output
+            .get_mut(op..op + literal_len)
+            .ok_or(EfsError::Corrupted)?
+            .copy_from_slice(literals);
+        ip += literal_len;
+        op += literal_len;
+        if ip == input.len() {
+            break;
+        }
+        let offset = match input.get(ip..ip + 2) {
+            Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            None => return Err(EfsError::Corrupted),
+        };
+        ip += 2;
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut ip)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > op || op + match_len > output.len() {
+            return Err(EfsError::Corrupted);
+        }
+        // 匹配可能和正在写入的部分重叠, 需要逐字节复制
+        for i in op..op + match_len {
+            output[i] = output[i - offset];
+        }
+        op += match_len;
+    }
+    Ok(op)
+}
+
+/// 4字节内容的哈希值
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// 写入一个序列
+fn emit(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_len = literals.len();
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literal_len.min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literal_len >= 15 {
+        write_length(out, literal_len - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+/// 写入长度的扩展字节
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// 读取长度的扩展字节
+fn read_length(input: &[u8], ip: &mut usize) -> Result<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *input.get(*ip).ok_or(EfsError::Corrupted)?;
+        *ip += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{compress, decompress};
+    use crate::{EfsError, COMPRESSED_CHUNK_SZ};
+
+    /// 生成不可压缩的伪随机数据
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        let mut output = vec![0u8; input.len()];
+        assert_eq!(decompress(&compressed, &mut output), Ok(input.len()));
+        assert!(output == input);
+        compressed
+    }
+
+    #[test]
+    fn round_trip_compressible_input() {
+        round_trip(&[]);
+        round_trip(b"abc");
+        // 重叠的匹配以及需要扩展字节的长度
+        let repeated: Vec<u8> = b"abcdefg".iter().copied().cycle().take(5000).collect();
+        assert!(round_trip(&repeated).len() < 100);
+        assert!(round_trip(&[0u8; COMPRESSED_CHUNK_SZ]).len() < 100);
+        let mut mixed = random_bytes(300);
+        mixed.extend_from_slice(&[7u8; 1000]);
+        mixed.extend(random_bytes(20));
+        mixed.extend_from_slice(&mixed.clone()[..300]);
+        assert!(round_trip(&mixed).len() < mixed.len() / 2);
+    }
+
+    #[test]
+    fn round_trip_incompressible_input() {
+        for len in [1, 15, 16, 300, COMPRESSED_CHUNK_SZ] {
+            let input = random_bytes(len);
+            // 不可压缩的数据只会多出标记字节和长度的扩展字节
+            assert!(round_trip(&input).len() <= len + 1 + len / 255 + 1);
+        }
+    }
+
+    #[test]
+    fn corrupted_input_is_rejected() {
+        let input: Vec<u8> = b"hello hello hello hello!".to_vec();
+        let compressed = compress(&input);
+        let mut output = vec![0u8; input.len()];
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 1], &mut output),
+            Err(EfsError::Corrupted)
+        );
+        let mut short = vec![0u8; input.len() - 1];
+        assert_eq!(
+            decompress(&compressed, &mut short),
+            Err(EfsError::Corrupted)
+        );
+        // 引用了输出之前的位置
+        assert_eq!(
+            decompress(&[0x00, 0x01, 0x00], &mut output),
+            Err(EfsError::Corrupted)
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use alloc::string::String;
+
+use crate::layout::DiskInode;
+
+/// 最多同时保存的快照数量
+pub const MAX_SNAPSHOTS: usize = 3;
+/// 快照名的最大长度
+pub const SNAPSHOT_NAME_LIMIT: usize = 27;
+
+/// 快照表, 占用数据区域中的一个块, 块编号记录在超级块中
+///
+/// refcounts是引用计数文件, 数据区域中的每个块对应一个字节,
+/// 记录除了第一个所有者之外还有多少个文件系统树引用这个块, 超出文件末尾或者位于空洞中的块为0;
+/// images中的每个文件保存一个快照的镜像, 大小为0的表示空闲:
+/// 快照头 | inode位图的副本 | inode区域的副本,
+/// inode区域中没有已分配inode的块在镜像中保持为空洞
+#[repr(C)]
+pub struct SnapshotTable {
+    pub refcounts: DiskInode,
+    pub images: [DiskInode; MAX_SNAPSHOTS],
+}
+
+/// 快照镜像的第一个块
+#[repr(C)]
+pub struct SnapshotHeader {
+    // 快照名, 以0结尾
+    name: [u8; SNAPSHOT_NAME_LIMIT + 1],
+    // 创建时间, 单位为秒
+    pub created: u32,
+}
+
+impl SnapshotHeader {
+    pub fn initialize(&mut self, name: &str, created: u32) {
+        let len = name.len().min(SNAPSHOT_NAME_LIMIT);
+        self.name = [0u8; SNAPSHOT_NAME_LIMIT + 1];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.created = created;
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(SNAPSHOT_NAME_LIMIT);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// 一个快照的信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    // 快照名
+    pub name: String,
+    // 创建时间, 单位为秒
+    pub created: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use crate::{fsck::fsck, test_util::create_fs, vfs::Inode, BLOCK_SZ};
+
+    #[test]
+    fn rollback_restores_old_content() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let file = root.create("file").unwrap();
+        file.write_at(0, &[1u8; 3 * BLOCK_SZ]).unwrap();
+        efs.lock().create_snapshot("before").unwrap();
+
+        // 修改被快照共享的块时先复制一份, 快照中的内容保持不变
+        file.write_at(BLOCK_SZ, &[2u8; 4 * BLOCK_SZ]).unwrap();
+        root.create("new").unwrap();
+        root.unlink("file").unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        drop(file);
+
+        efs.lock().rollback_snapshot("before").unwrap();
+        assert!(fsck(&efs, false).unwrap().is_clean());
+        assert!(root.find("new").is_err());
+        let file = root.find("file").unwrap();
+        let mut buf = vec![0u8; 4 * BLOCK_SZ];
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 3 * BLOCK_SZ);
+        assert!(buf[..3 * BLOCK_SZ].iter().all(|&byte| byte == 1));
+
+        efs.lock().delete_snapshot("before").unwrap();
+        assert!(!efs.lock().has_snapshots());
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,105 @@
+//! 测试共用的辅助设施
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{block_dev::BlockDevice, efs::EasyFileSystem, EfsError, Result, BLOCK_SZ};
+
+/// 在一个有total_blocks个块的内存设备上创建开启了features的文件系统
+///
+/// 文件系统被释放时会把设备从块缓存中移除, 测试结束时不需要另外清理
+pub fn create_fs(total_blocks: u32, features: u32) -> (Arc<MemDevice>, Arc<Mutex<EasyFileSystem>>) {
+    let device = Arc::new(MemDevice::new(total_blocks as usize));
+    let efs =
+        EasyFileSystem::create_with_features(device.clone(), total_blocks, 1, features).unwrap();
+    (device, efs)
+}
+
+/// 记录下来的一次写入, (块编号, 块的内容)
+pub type BlockWrite = (usize, [u8; BLOCK_SZ]);
+
+/// 测试中使用的内存块设备, 可以记录下每一次写入, 用来模拟在任意一次写入之后崩溃
+pub struct MemDevice {
+    // 设备上所有块的内容
+    data: Mutex<Vec<u8>>,
+    // 开始记录之后的每一次写入
+    writes: Mutex<Option<Vec<BlockWrite>>>,
+    // 读请求的次数, 一次读取多个块只算一次
+    read_requests: AtomicUsize,
+}
+
+impl MemDevice {
+    /// 创建一个有blocks个块, 内容全为0的设备
+    pub fn new(blocks: usize) -> Self {
+        Self::from_image(vec![0u8; blocks * BLOCK_SZ])
+    }
+
+    /// 以image作为设备的内容
+    pub fn from_image(image: Vec<u8>) -> Self {
+        Self {
+            data: Mutex::new(image),
+            writes: Mutex::new(None),
+            read_requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// 设备当前的内容
+    pub fn image(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+
+    /// 开始记录之后的写入
+    pub fn start_recording(&self) {
+        *self.writes.lock() = Some(Vec::new());
+    }
+
+    /// 停止记录并返回记录下来的写入
+    pub fn take_writes(&self) -> Vec<BlockWrite> {
+        self.writes.lock().take().unwrap_or_default()
+    }
+
+    /// 到目前为止的读请求次数
+    pub fn read_requests(&self) -> usize {
+        self.read_requests.load(Ordering::Relaxed)
+    }
+
+    /// 在image上依次重放前count次写入, 得到在第count次写入之后崩溃时的磁盘内容
+    pub fn crash_image(image: &[u8], writes: &[(usize, [u8; BLOCK_SZ])], count: usize) -> Vec<u8> {
+        let mut image = image.to_vec();
+        for (block_id, data) in writes[..count].iter() {
+            image[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ].copy_from_slice(data);
+        }
+        image
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.read_blocks(block_id, buf)
+    }
+
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.read_requests.fetch_add(1, Ordering::Relaxed);
+        let data = self.data.lock();
+        let blocks = data
+            .get(start_block_id * BLOCK_SZ..start_block_id * BLOCK_SZ + buf.len())
+            .ok_or(EfsError::IoError)?;
+        buf.copy_from_slice(blocks);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()> {
+        let mut data = self.data.lock();
+        let block = data
+            .get_mut(block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ)
+            .ok_or(EfsError::IoError)?;
+        block.copy_from_slice(buf);
+        if let Some(writes) = self.writes.lock().as_mut() {
+            let mut copy = [0u8; BLOCK_SZ];
+            copy.copy_from_slice(buf);
+            writes.push((block_id, copy));
+        }
+        Ok(())
+    }
+}
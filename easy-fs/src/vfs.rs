@@ -0,0 +1,1317 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::{Mutex, MutexGuard};
+
+use crate::{
+    block_cache::get_block_cache,
+    block_dev::BlockDevice,
+    clock::now,
+    dir_index::{self, DIRENTS_PER_BLOCK, DIR_INDEX_THRESHOLD_BLOCKS},
+    efs::EasyFileSystem,
+    layout::{DirEntry, DiskInode, DiskInodeType, CHUNK_BLOCKS, COMPRESSED_CHUNK_SZ, DIRENT_SZ},
+    xattr::{self, XattrBlock, XattrEntry, XATTR_NAME_LIMIT},
+    EfsError, Result, BLOCK_SZ,
+};
+
+/// 路径查找时最多跟随的符号链接次数, 超过时认为出现了循环链接
+const MAX_SYMLINK_DEPTH: usize = 8;
+/// truncate和fallocate每次事务最多处理的数据块数量
+const RESIZE_BATCH_BLOCKS: usize = 32;
+/// 访问时间不早于修改时间和变化时间时, 距离上一次更新超过这么多秒才再次更新
+const RELATIME_INTERVAL: u32 = 24 * 60 * 60;
+
+/// 文件的状态信息, 类似于stat系统调用的结果
+///
+/// 版本1的镜像中没有权限、所有者以及时间戳, 这时它们为默认值
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub inode_id: u32,
+    pub type_: DiskInodeType,
+    // 权限位
+    pub mode: u32,
+    // 硬链接计数
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // 文件的字节大小
+    pub size: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+/// 向目录中添加目录项时选择的位置
+enum DirentSlot {
+    // 写入这个位置的目录项, 位置可能在目录末尾之后
+    Free(usize),
+    // 重建哈希目录, 重建后至少有这么多个块
+    Rebuild(u32),
+}
+
+/// 暴露给内核使用的索引节点
+///
+/// 只记录DiskInode在磁盘上的位置, 所有操作都通过块缓存访问磁盘上的DiskInode
+pub struct Inode {
+    // inode编号
+    inode_id: u32,
+    // DiskInode所在的块编号
+    block_id: usize,
+    // DiskInode在块内的偏移
+    block_offset: usize,
+    fs: Arc<Mutex<EasyFileSystem>>,
+    block_device: Arc<dyn BlockDevice>,
+}
+
+impl Inode {
+    pub fn new(
+        inode_id: u32,
+        block_id: u32,
+        block_offset: usize,
+        fs: Arc<Mutex<EasyFileSystem>>,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Self {
+        Self {
+            inode_id,
+            block_id: block_id as usize,
+            block_offset,
+            fs,
+            block_device,
+        }
+    }
+
+    /// 获取文件系统的根目录
+    pub fn root_inode(efs: &Arc<Mutex<EasyFileSystem>>) -> Self {
+        let block_device = Arc::clone(&efs.lock().block_device);
+        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
+        Self::new(0, block_id, block_offset, Arc::clone(efs), block_device)
+    }
+
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+
+    pub fn is_dir(&self) -> Result<bool> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    pub fn is_file(&self) -> Result<bool> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_file())
+    }
+
+    pub fn is_symlink(&self) -> Result<bool> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_symlink())
+    }
+
+    /// 硬链接计数
+    pub fn nlink(&self) -> Result<u32> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+
+    /// 获取文件的状态信息
+    pub fn stat(&self) -> Result<Stat> {
+        let fs = self.fs.lock();
+        let (type_, nlink, size) = self.read_disk_inode(|disk_inode| {
+            Ok((disk_inode.type_()?, disk_inode.nlink, disk_inode.size))
+        })??;
+        let (mode, uid, gid, atime, mtime, ctime) = fs
+            .read_inode_meta(self.inode_id, |meta| {
+                (
+                    meta.mode, meta.uid, meta.gid, meta.atime, meta.mtime, meta.ctime,
+                )
+            })?
+            .unwrap_or((Self::default_mode(type_), 0, 0, 0, 0, 0));
+        Ok(Stat {
+            inode_id: self.inode_id,
+            type_,
+            mode,
+            nlink,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+        })
+    }
+
+    /// 修改文件的权限位
+    ///
+    /// 镜像中没有保存权限时返回EfsError::Unsupported
+    pub fn chmod(&self, mode: u32) -> Result<()> {
+        self.modify_fs(|fs| {
+            fs.modify_inode_meta(self.inode_id, |meta| {
+                meta.mode = mode & 0o7777;
+                meta.ctime = now();
+            })?
+            .ok_or(EfsError::Unsupported)?;
+            fs.commit()
+        })
+    }
+
+    /// 修改文件的所有者
+    ///
+    /// 镜像中没有保存所有者时返回EfsError::Unsupported
+    pub fn chown(&self, uid: u32, gid: u32) -> Result<()> {
+        self.modify_fs(|fs| {
+            fs.modify_inode_meta(self.inode_id, |meta| {
+                meta.uid = uid;
+                meta.gid = gid;
+                meta.ctime = now();
+            })?
+            .ok_or(EfsError::Unsupported)?;
+            fs.commit()
+        })
+    }
+
+    /// 新建的inode的默认权限位
+    fn default_mode(type_: DiskInodeType) -> u32 {
+        match type_ {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Directory => 0o755,
+            DiskInodeType::SymLink => 0o777,
+        }
+    }
+
+    /// 内容被修改后更新inode的修改时间
+    fn touch_modified(fs: &MutexGuard<EasyFileSystem>, inode_id: u32) -> Result<()> {
+        let now = now();
+        fs.modify_inode_meta(inode_id, |meta| {
+            meta.mtime = now;
+            meta.ctime = now;
+        })?;
+        Ok(())
+    }
+
+    /// inode本身(如硬链接计数)被修改后更新inode的变化时间
+    fn touch_changed(fs: &MutexGuard<EasyFileSystem>, inode_id: u32) -> Result<()> {
+        fs.modify_inode_meta(inode_id, |meta| meta.ctime = now())?;
+        Ok(())
+    }
+
+    /// 持有文件系统的锁执行一个修改文件系统的操作
+    ///
+    /// 操作返回错误时通过EasyFileSystem::abort放弃还没有提交的修改, 之前分批提交的部分仍然保留;
+    /// 文件系统以只读方式打开时不执行操作, 直接返回EfsError::ReadOnly
+    fn modify_fs<V>(
+        &self,
+        f: impl FnOnce(&mut MutexGuard<EasyFileSystem>) -> Result<V>,
+    ) -> Result<V> {
+        let mut fs = self.fs.lock();
+        if fs.is_read_only() {
+            return Err(EfsError::ReadOnly);
+        }
+        let result = f(&mut fs);
+        if result.is_err() {
+            fs.abort()?;
+        }
+        result
+    }
+
+    /// 对磁盘上的DiskInode进行只读访问
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> Result<V> {
+        get_block_cache(self.block_id, Arc::clone(&self.block_device))?
+            .lock()
+            .read(self.block_offset, f)
+    }
+
+    /// 对磁盘上的DiskInode进行修改
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> Result<V> {
+        get_block_cache(self.block_id, Arc::clone(&self.block_device))?
+            .lock()
+            .modify(self.block_offset, f)
+    }
+
+    /// 根据inode编号构造一个Inode
+    fn get_inode(&self, fs: &MutexGuard<EasyFileSystem>, inode_id: u32) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            Arc::clone(&self.fs),
+            Arc::clone(&self.block_device),
+        ))
+    }
+
+    /// 目录中名为name的目录项可能位于的所有位置, 按照检查的顺序排列
+    ///
+    /// 哈希目录只需要检查探测范围内的块, 其他目录需要检查所有的目录项;
+    /// 哈希目录的大小不是块大小的整数倍时返回EfsError::Corrupted
+    fn dirent_slots(name: &str, disk_inode: &DiskInode) -> Result<Vec<usize>> {
+        let size = disk_inode.size as usize;
+        if !disk_inode.is_hashed() {
+            return Ok((0..size / DIRENT_SZ).collect());
+        }
+        if size == 0 || !size.is_multiple_of(BLOCK_SZ) {
+            return Err(EfsError::Corrupted);
+        }
+        Ok(dir_index::probe_blocks(name, (size / BLOCK_SZ) as u32)
+            .flat_map(|block| {
+                let first = block as usize * DIRENTS_PER_BLOCK;
+                first..first + DIRENTS_PER_BLOCK
+            })
+            .collect())
+    }
+
+    /// 在目录下根据文件名查找目录项, 返回目录项的位置以及inode编号, 不存在时返回None
+    ///
+    /// 不是目录时返回EfsError::NotDir
+    fn find_dirent(&self, name: &str, disk_inode: &DiskInode) -> Result<Option<(usize, u32)>> {
+        if !disk_inode.is_dir() {
+            return Err(EfsError::NotDir);
+        }
+        if name.is_empty() {
+            return Ok(None);
+        }
+        let mut dirent = DirEntry::empty();
+        for i in Self::dirent_slots(name, disk_inode)? {
+            disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device)?;
+            if dirent.name() == name {
+                return Ok(Some((i, dirent.inode_number())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 在目录下根据文件名查找inode编号, 不存在时返回None
+    ///
+    /// 不是目录时返回EfsError::NotDir
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Result<Option<u32>> {
+        Ok(self
+            .find_dirent(name, disk_inode)?
+            .map(|(_, inode_id)| inode_id))
+    }
+
+    /// 在inode_id对应的目录下根据文件名查找inode编号
+    fn find_inode_id_in(
+        &self,
+        fs: &MutexGuard<EasyFileSystem>,
+        dir_inode_id: u32,
+        name: &str,
+    ) -> Result<Option<u32>> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(dir_inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                self.find_inode_id(name, disk_inode)
+            })?
+    }
+
+    /// 读取inode_id对应的符号链接的目标路径, 不是符号链接时返回None
+    fn read_link_of(
+        &self,
+        fs: &MutexGuard<EasyFileSystem>,
+        inode_id: u32,
+    ) -> Result<Option<String>> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                if !disk_inode.is_symlink() {
+                    return Ok(None);
+                }
+                let mut buf = alloc::vec![0u8; disk_inode.size as usize];
+                disk_inode.read_at(0, &mut buf, &self.block_device)?;
+                String::from_utf8(buf)
+                    .map(Some)
+                    .map_err(|_| EfsError::Corrupted)
+            })?
+    }
+
+    /// 从dir_inode_id对应的目录开始根据路径查找inode编号
+    ///
+    /// 路径中间的符号链接总是会被跟随, 最后一个部分是否跟随由follow_link决定;
+    /// depth记录已经跟随的符号链接次数, 用于检测循环链接
+    fn resolve_path(
+        &self,
+        fs: &MutexGuard<EasyFileSystem>,
+        dir_inode_id: u32,
+        path: &str,
+        follow_link: bool,
+        depth: &mut usize,
+    ) -> Result<u32> {
+        let mut inode_id = if path.starts_with('/') {
+            0
+        } else {
+            dir_inode_id
+        };
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        for (i, name) in names.iter().enumerate() {
+            let parent_inode_id = inode_id;
+            inode_id = self
+                .find_inode_id_in(fs, parent_inode_id, name)?
+                .ok_or(EfsError::NotFound)?;
+            if i == names.len() - 1 && !follow_link {
+                break;
+            }
+            if let Some(target) = self.read_link_of(fs, inode_id)? {
+                *depth += 1;
+                if *depth > MAX_SYMLINK_DEPTH {
+                    return Err(EfsError::TooManyLinks);
+                }
+                // 相对路径的符号链接从链接所在的目录开始查找
+                inode_id = self.resolve_path(fs, parent_inode_id, &target, true, depth)?;
+            }
+        }
+        Ok(inode_id)
+    }
+
+    /// 根据路径查找文件
+    ///
+    /// 路径可以由多个以'/'分隔的部分组成, 如`a/b/c`, 从当前目录开始逐级查找;
+    /// 以'/'开头的路径则从根目录开始查找, 路径中的符号链接都会被跟随
+    pub fn find(&self, path: &str) -> Result<Arc<Inode>> {
+        self.lookup(path, true)
+    }
+
+    /// 根据路径查找文件
+    ///
+    /// follow_link为false时不跟随路径最后一个部分的符号链接, 返回符号链接本身;
+    /// 文件不存在时返回EfsError::NotFound, 路径中间的部分不是目录时返回EfsError::NotDir,
+    /// 符号链接的层数过多时返回EfsError::TooManyLinks
+    pub fn lookup(&self, path: &str, follow_link: bool) -> Result<Arc<Inode>> {
+        let fs = self.fs.lock();
+        let inode_id = self.resolve_path(&fs, self.inode_id, path, follow_link, &mut 0)?;
+        Ok(self.get_inode(&fs, inode_id))
+    }
+
+    /// 读取符号链接的目标路径, 当前inode不是符号链接时返回EfsError::InvalidArgument
+    pub fn readlink(&self) -> Result<String> {
+        let fs = self.fs.lock();
+        self.read_link_of(&fs, self.inode_id)?
+            .ok_or(EfsError::InvalidArgument)
+    }
+
+    /// 在目录中为名为name的目录项选择位置
+    ///
+    /// 优先复用被删除的目录项留下的空位, 哈希目录只在探测范围内寻找空位;
+    /// 没有空位时线性目录追加到末尾, 哈希目录以及超过阈值的线性目录需要重建哈希目录
+    fn choose_dirent_slot(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        fs: &MutexGuard<EasyFileSystem>,
+    ) -> Result<DirentSlot> {
+        let mut slot = DirEntry::empty();
+        for i in Self::dirent_slots(name, disk_inode)? {
+            disk_inode.read_at(i * DIRENT_SZ, slot.as_bytes_mut(), &self.block_device)?;
+            if slot.name().is_empty() {
+                return Ok(DirentSlot::Free(i));
+            }
+        }
+        let file_count = disk_inode.size as usize / DIRENT_SZ;
+        if disk_inode.is_hashed() {
+            Ok(DirentSlot::Rebuild(
+                file_count as u32 / DIRENTS_PER_BLOCK as u32 * 2,
+            ))
+        } else if fs.has_dir_index() && file_count >= DIR_INDEX_THRESHOLD_BLOCKS * DIRENTS_PER_BLOCK
+        {
+            Ok(DirentSlot::Rebuild(0))
+        } else {
+            Ok(DirentSlot::Free(file_count))
+        }
+    }
+
+    /// 为名为name的目录项选择位置, 需要时先重建哈希目录, 返回目录项的下标
+    ///
+    /// 重建由rebuild_dir_index分为若干次事务完成, 重建后新的目录项最多还需要分配一个块
+    fn prepare_dirent_slot(
+        &self,
+        name: &str,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<usize> {
+        loop {
+            match self
+                .read_disk_inode(|disk_inode| self.choose_dirent_slot(name, disk_inode, fs))??
+            {
+                DirentSlot::Free(idx) => return Ok(idx),
+                DirentSlot::Rebuild(min_buckets) => {
+                    fs.rebuild_dir_index(self.inode_id, min_buckets)?
+                }
+            }
+        }
+    }
+
+    /// 将目录项写入目录中下标为idx的位置, 位置由prepare_dirent_slot选择
+    fn add_dir_entry(
+        &self,
+        idx: usize,
+        dirent: &DirEntry,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        // 追加时分配新的块, 复用的空位所在的块被快照共享时先复制一份
+        fs.fill_holes(disk_inode, idx * DIRENT_SZ, DIRENT_SZ)?;
+        disk_inode.write_at(idx * DIRENT_SZ, dirent.as_bytes(), &self.block_device)?;
+        Ok(())
+    }
+
+    /// 从目录中删除一个目录项, 被删除的目录项会被清零留作空位
+    fn remove_dir_entry(
+        &self,
+        name: &str,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        if let Some((i, _)) = self.find_dirent(name, disk_inode)? {
+            fs.unshare(disk_inode, i * DIRENT_SZ, DIRENT_SZ)?;
+            disk_inode.write_at(
+                i * DIRENT_SZ,
+                DirEntry::empty().as_bytes(),
+                &self.block_device,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// 文件名是否可以用于新建的目录项
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+    }
+
+    /// 在当前目录下根据文件名查找将要被删除的inode编号
+    ///
+    /// 文件名不合法时返回EfsError::InvalidName, 当前inode不是目录时返回EfsError::NotDir,
+    /// 文件不存在时返回EfsError::NotFound
+    fn find_child(&self, name: &str) -> Result<u32> {
+        if !Self::is_valid_name(name) {
+            return Err(EfsError::InvalidName);
+        }
+        self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode))??
+            .ok_or(EfsError::NotFound)
+    }
+
+    /// 检查当前目录下是否可以新建名为name的目录项
+    ///
+    /// 文件名不合法时返回EfsError::InvalidName或EfsError::NameTooLong,
+    /// 当前inode不是目录时返回EfsError::NotDir, 文件已经存在时返回EfsError::Exists
+    fn check_new_name(&self, name: &str) -> Result<()> {
+        if !Self::is_valid_name(name) {
+            return Err(EfsError::InvalidName);
+        }
+        DirEntry::new(name, 0)?;
+        match self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode))?? {
+            Some(_) => Err(EfsError::Exists),
+            None => Ok(()),
+        }
+    }
+
+    /// 在当前目录下创建一个指定类型的inode
+    ///
+    /// data为新inode的初始内容, 和目录项在同一个事务中写入;
+    /// 中途失败时modify_fs会放弃已经做出的修改
+    fn create_inode(&self, name: &str, type_: DiskInodeType, data: &[u8]) -> Result<Arc<Inode>> {
+        self.modify_fs(|fs| {
+            self.check_new_name(name)?;
+            let idx = self.prepare_dirent_slot(name, fs)?;
+            // 分配并初始化新的inode
+            let new_inode_id = fs.alloc_inode()?;
+            let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                    new_inode.initialize(type_, fs.new_inode_flags(type_));
+                    if type_ == DiskInodeType::Directory {
+                        fs.init_dir(new_inode, new_inode_id, self.inode_id)?;
+                    }
+                    if !data.is_empty() {
+                        fs.fill_holes(new_inode, 0, data.len())?;
+                        new_inode.write_at(0, data, &self.block_device)?;
+                    }
+                    Ok(())
+                })??;
+            fs.modify_inode_meta(new_inode_id, |meta| {
+                meta.initialize(Self::default_mode(type_), now());
+            })?;
+            // 在当前目录中添加目录项
+            let dirent = DirEntry::new(name, new_inode_id)?;
+            self.modify_disk_inode(|dir_inode| self.add_dir_entry(idx, &dirent, dir_inode, fs))??;
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()?;
+            Ok(self.get_inode(fs, new_inode_id))
+        })
+    }
+
+    /// 在当前目录下创建一个文件
+    ///
+    /// 文件已经存在时返回EfsError::Exists
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File, &[])
+    }
+
+    /// 在当前目录下创建一个子目录
+    ///
+    /// 新目录中会包含指向自身的"."以及指向当前目录的".."
+    pub fn mkdir(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory, &[])
+    }
+
+    /// 在当前目录下创建一个指向target的符号链接
+    ///
+    /// target不需要存在, 只有在路径查找时才会被解析
+    pub fn symlink(&self, target: &str, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::SymLink, target.as_bytes())
+    }
+
+    /// 删除当前目录下的一个空目录
+    ///
+    /// 不是目录时返回EfsError::NotDir, 目录非空时返回EfsError::NotEmpty
+    pub fn rmdir(&self, name: &str) -> Result<()> {
+        self.modify_fs(|fs| {
+            let inode_id = self.find_child(name)?;
+            let inode = self.get_inode(fs, inode_id);
+            inode.read_disk_inode(|disk_inode| {
+                if !disk_inode.is_dir() {
+                    return Err(EfsError::NotDir);
+                }
+                if !self.is_empty_dir(disk_inode)? {
+                    return Err(EfsError::NotEmpty);
+                }
+                Ok(())
+            })??;
+            // 先删除目录项, 复制被快照共享的目录块失败时子目录保持不变
+            self.modify_disk_inode(|dir_inode| self.remove_dir_entry(name, dir_inode, fs))??;
+            inode.modify_disk_inode(|disk_inode| {
+                for data_block in disk_inode.clear_size(&self.block_device)? {
+                    fs.dealloc_data(data_block)?;
+                }
+                Ok(())
+            })??;
+            Self::dealloc_xattrs(fs, inode_id)?;
+            fs.dealloc_inode(inode_id)?;
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 在当前目录下创建一个指向old_path的硬链接
+    ///
+    /// old_path是目录时返回EfsError::IsDir, new_name已经存在时返回EfsError::Exists
+    pub fn link(&self, old_path: &str, new_name: &str) -> Result<()> {
+        self.modify_fs(|fs| {
+            self.check_new_name(new_name)?;
+            let inode_id = self.resolve_path(fs, self.inode_id, old_path, false, &mut 0)?;
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            let inode_block = get_block_cache(block_id as usize, Arc::clone(&self.block_device))?;
+            if inode_block
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| disk_inode.is_dir())?
+            {
+                return Err(EfsError::IsDir);
+            }
+            // 先添加目录项, 目录没有空间时硬链接计数保持不变
+            let idx = self.prepare_dirent_slot(new_name, fs)?;
+            let dirent = DirEntry::new(new_name, inode_id)?;
+            self.modify_disk_inode(|dir_inode| self.add_dir_entry(idx, &dirent, dir_inode, fs))??;
+            inode_block
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.nlink += 1;
+                })?;
+            Self::touch_changed(fs, inode_id)?;
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 删除当前目录下的一个目录项
+    ///
+    /// 只有当inode的硬链接计数减为0时才会回收它的数据块和inode,
+    /// 目录需要通过rmdir删除, 是目录时返回EfsError::IsDir
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        self.modify_fs(|fs| {
+            let inode_id = self.find_child(name)?;
+            let inode = self.get_inode(fs, inode_id);
+            if inode.read_disk_inode(|disk_inode| disk_inode.is_dir())? {
+                return Err(EfsError::IsDir);
+            }
+            // 先删除目录项, 复制被快照共享的目录块失败时文件保持不变
+            self.modify_disk_inode(|dir_inode| self.remove_dir_entry(name, dir_inode, fs))??;
+            Self::touch_modified(fs, self.inode_id)?;
+            let (nlink, blocks) = inode.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink = disk_inode.nlink.saturating_sub(1);
+                (disk_inode.nlink, disk_inode.data_blocks() as usize)
+            })?;
+            if nlink > 0 {
+                Self::touch_changed(fs, inode_id)?;
+                return fs.commit();
+            }
+            if blocks > RESIZE_BATCH_BLOCKS {
+                // 很大的文件无法在一次事务中回收, 删除目录项之后再分批回收,
+                // 中途崩溃时留下的inode不再可达, 由fsck回收
+                fs.commit()?;
+                inode.shrink_blocks(fs, RESIZE_BATCH_BLOCKS)?;
+            }
+            inode.modify_disk_inode(|disk_inode| {
+                for data_block in disk_inode.clear_size(&self.block_device)? {
+                    fs.dealloc_data(data_block)?;
+                }
+                Ok(())
+            })??;
+            Self::dealloc_xattrs(fs, inode_id)?;
+            fs.dealloc_inode(inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 读取inode的扩展属性块编号以及其中的所有条目, 没有扩展属性块时编号为0
+    ///
+    /// 镜像中没有inode元数据时返回EfsError::Unsupported
+    fn read_xattrs(&self, fs: &MutexGuard<EasyFileSystem>) -> Result<(u32, Vec<XattrEntry>)> {
+        let xattr_block = fs
+            .read_inode_meta(self.inode_id, |meta| meta.xattr_block)?
+            .ok_or(EfsError::Unsupported)?;
+        if xattr_block == 0 {
+            return Ok((0, Vec::new()));
+        }
+        let entries = get_block_cache(xattr_block as usize, Arc::clone(&self.block_device))?
+            .lock()
+            .read(0, xattr::decode)?;
+        Ok((xattr_block, entries))
+    }
+
+    /// 回收inode的扩展属性块, 在回收inode之前调用
+    fn dealloc_xattrs(fs: &mut MutexGuard<EasyFileSystem>, inode_id: u32) -> Result<()> {
+        let xattr_block = fs
+            .read_inode_meta(inode_id, |meta| meta.xattr_block)?
+            .unwrap_or(0);
+        if xattr_block != 0 {
+            fs.dealloc_data(xattr_block)?;
+        }
+        Ok(())
+    }
+
+    /// 读取一个扩展属性的值, 不存在时返回EfsError::NotFound
+    pub fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let fs = self.fs.lock();
+        let (_, entries) = self.read_xattrs(&fs)?;
+        entries
+            .into_iter()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, value)| value)
+            .ok_or(EfsError::NotFound)
+    }
+
+    /// 列出所有扩展属性的名字
+    pub fn list_xattr(&self) -> Result<Vec<String>> {
+        let fs = self.fs.lock();
+        let (_, entries) = self.read_xattrs(&fs)?;
+        Ok(entries.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// 设置一个扩展属性, 已经存在时覆盖原来的值
+    ///
+    /// 第一次设置时会从数据位图中分配扩展属性块;
+    /// 镜像中没有inode元数据时返回EfsError::Unsupported, 名字过长时返回EfsError::NameTooLong,
+    /// 扩展属性块放不下时返回EfsError::NoSpace
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        self.modify_fs(|fs| {
+            if name.is_empty() {
+                return Err(EfsError::InvalidName);
+            }
+            if name.len() > XATTR_NAME_LIMIT {
+                return Err(EfsError::NameTooLong);
+            }
+            let (mut xattr_block, mut entries) = self.read_xattrs(fs)?;
+            match entries
+                .iter_mut()
+                .find(|(entry_name, _)| entry_name == name)
+            {
+                Some((_, old_value)) => *old_value = value.to_vec(),
+                None => entries.push((String::from(name), value.to_vec())),
+            }
+            let data = xattr::encode(&entries).ok_or(EfsError::NoSpace)?;
+            xattr_block = if xattr_block == 0 {
+                fs.alloc_data()?
+            } else {
+                fs.unshare_block(xattr_block)?
+            };
+            get_block_cache(xattr_block as usize, Arc::clone(&self.block_device))?
+                .lock()
+                .modify(0, |block: &mut XattrBlock| *block = data)?;
+            fs.modify_inode_meta(self.inode_id, |meta| {
+                meta.xattr_block = xattr_block;
+                meta.ctime = now();
+            })?;
+            fs.commit()
+        })
+    }
+
+    /// 删除一个扩展属性, 不存在时返回EfsError::NotFound
+    ///
+    /// 删除最后一个扩展属性时会回收扩展属性块
+    pub fn remove_xattr(&self, name: &str) -> Result<()> {
+        self.modify_fs(|fs| {
+            let (mut xattr_block, mut entries) = self.read_xattrs(fs)?;
+            let len = entries.len();
+            entries.retain(|(entry_name, _)| entry_name != name);
+            if entries.len() == len {
+                return Err(EfsError::NotFound);
+            }
+            if entries.is_empty() {
+                fs.dealloc_data(xattr_block)?;
+                xattr_block = 0;
+            } else {
+                // 删除条目之后剩下的条目一定能放下
+                let data = xattr::encode(&entries).ok_or(EfsError::Corrupted)?;
+                xattr_block = fs.unshare_block(xattr_block)?;
+                get_block_cache(xattr_block as usize, Arc::clone(&self.block_device))?
+                    .lock()
+                    .modify(0, |block: &mut XattrBlock| *block = data)?;
+            }
+            fs.modify_inode_meta(self.inode_id, |meta| {
+                meta.xattr_block = xattr_block;
+                meta.ctime = now();
+            })?;
+            fs.commit()
+        })
+    }
+
+    /// 目录中是否只剩下"."和".."
+    fn is_empty_dir(&self, disk_inode: &DiskInode) -> Result<bool> {
+        Ok(self.dir_entries(disk_inode)?.is_empty())
+    }
+
+    /// 获取目录中除了"."、".."和空位之外的所有目录项
+    fn dir_entries(&self, disk_inode: &DiskInode) -> Result<Vec<DirEntry>> {
+        let file_count = disk_inode.size as usize / DIRENT_SZ;
+        let mut v: Vec<DirEntry> = Vec::new();
+        for i in 0..file_count {
+            let mut dirent = DirEntry::empty();
+            disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device)?;
+            if !matches!(dirent.name(), "" | "." | "..") {
+                v.push(dirent);
+            }
+        }
+        Ok(v)
+    }
+
+    /// 列出当前目录下的所有文件名, 不包括"."和".."
+    ///
+    /// 当前inode不是目录时返回EfsError::NotDir
+    pub fn ls(&self) -> Result<Vec<String>> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(EfsError::NotDir);
+            }
+            Ok(self
+                .dir_entries(disk_inode)?
+                .iter()
+                .map(|dirent| String::from(dirent.name()))
+                .collect())
+        })?
+    }
+
+    /// 从文件的offset字节处读取数据
+    ///
+    /// 访问时间按照relatime的规则只在块缓存中更新, 随之后的下一次事务提交写入磁盘;
+    /// 读取本身不会提交事务, 因此也可以在只读的设备上进行
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let fs = self.fs.lock();
+        let size = self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_compressed() {
+                fs.read_compressed(disk_inode, offset, buf)
+            } else {
+                disk_inode.read_at(offset, buf, &self.block_device)
+            }
+        })??;
+        let now = now();
+        let stale = fs.read_inode_meta(self.inode_id, |meta| {
+            meta.atime != now
+                && (meta.atime <= meta.mtime
+                    || meta.atime <= meta.ctime
+                    || now.wrapping_sub(meta.atime) >= RELATIME_INTERVAL)
+        })?;
+        if stale == Some(true) {
+            fs.modify_inode_meta(self.inode_id, |meta| meta.atime = now)?;
+        }
+        Ok(size)
+    }
+
+    /// 向文件的offset字节处写入数据, 文件空间不足时会自动扩容
+    ///
+    /// 只为写入涉及的块分配数据块, 跳过的部分成为空洞;
+    /// 每RESIZE_BATCH_BLOCKS个块作为一次事务提交, 以免一次事务超过日志的容量;
+    /// 超过文件最大大小时返回EfsError::FileTooLarge, 此时不会写入任何数据;
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时之前的批次可能已经写入
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.modify_fs(|fs| {
+            let end = offset + buf.len();
+            if end > self.read_disk_inode(|disk_inode| disk_inode.max_size())? {
+                return Err(EfsError::FileTooLarge);
+            }
+            // 每批都结束在RESIZE_BATCH_BLOCKS个块的边界上, 压缩文件的块组不会被拆分到两批中
+            let batch_size = RESIZE_BATCH_BLOCKS * BLOCK_SZ;
+            let mut pos = offset;
+            loop {
+                let batch_end = ((pos / batch_size + 1) * batch_size).min(end);
+                let data = &buf[pos - offset..batch_end - offset];
+                self.modify_disk_inode(|disk_inode| {
+                    if disk_inode.is_compressed() {
+                        return fs.write_compressed(disk_inode, pos, data);
+                    }
+                    fs.fill_holes(disk_inode, pos, data.len())?;
+                    disk_inode.write_at(pos, data, &self.block_device)
+                })??;
+                Self::touch_modified(fs, self.inode_id)?;
+                fs.commit()?;
+                if batch_end == end {
+                    break;
+                }
+                pos = batch_end;
+            }
+            Ok(buf.len())
+        })
+    }
+
+    /// 清空文件内容并回收数据块
+    ///
+    /// 和truncate到0一样分批回收数据块
+    pub fn clear(&self) -> Result<()> {
+        self.truncate(0)
+    }
+
+    /// 在文件的[offset, offset + len)范围内打洞, 文件大小保持不变
+    ///
+    /// 完全落在范围内的数据块会被回收到数据位图, 之后读出的都是0;
+    /// 范围两端不足一个块的部分直接写入0, 压缩文件以块组为单位回收;
+    /// 回收的块分批作为多次事务提交, 中途崩溃时只有一部分范围成为空洞
+    pub fn punch_hole(&self, offset: usize, len: usize) -> Result<()> {
+        self.modify_fs(|fs| {
+            let blocks = self.modify_disk_inode(|disk_inode| {
+                let size = disk_inode.size as usize;
+                let end = (offset + len).min(size);
+                if offset >= end {
+                    return Ok(None);
+                }
+                let unit = if disk_inode.is_compressed() {
+                    COMPRESSED_CHUNK_SZ
+                } else {
+                    BLOCK_SZ
+                };
+                let start_unit = offset.div_ceil(unit);
+                // 文件的最后一个块在文件末尾之后没有有效数据, 可以整块回收
+                let end_unit = if end == size {
+                    size.div_ceil(unit)
+                } else {
+                    end / unit
+                };
+                if start_unit < end_unit {
+                    self.zero_range(fs, disk_inode, offset, start_unit * unit)?;
+                    self.zero_range(fs, disk_inode, end_unit * unit, end)?;
+                    let blocks_per_unit = unit / BLOCK_SZ;
+                    Ok(Some((
+                        start_unit * blocks_per_unit,
+                        end_unit * blocks_per_unit,
+                    )))
+                } else {
+                    // 范围中没有完整的块, 但仍然可能跨越两个块
+                    let boundary = (offset / unit + 1) * unit;
+                    self.zero_range(fs, disk_inode, offset, end.min(boundary))?;
+                    self.zero_range(fs, disk_inode, boundary, end)?;
+                    Ok(None)
+                }
+            })??;
+            if let Some((mut start_block, end_block)) = blocks {
+                // 每批的长度是块组大小的整数倍, 压缩文件的块组不会被拆分
+                while start_block < end_block {
+                    let batch_end = (start_block + RESIZE_BATCH_BLOCKS).min(end_block);
+                    self.modify_disk_inode(|disk_inode| {
+                        fs.punch_hole(disk_inode, start_block as u32, batch_end as u32)
+                    })??;
+                    Self::touch_modified(fs, self.inode_id)?;
+                    fs.commit()?;
+                    start_block = batch_end;
+                }
+            }
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 从文件末尾开始分批回收数据块和不再需要的索引块, 直到文件只剩下前new_blocks个块
+    ///
+    /// 每批作为一次事务提交, 文件大小随每一批减小, 不会超过剩下的块能够容纳的大小
+    fn shrink_blocks(&self, fs: &mut MutexGuard<EasyFileSystem>, new_blocks: usize) -> Result<()> {
+        let mut end_block = self.read_disk_inode(|disk_inode| disk_inode.data_blocks() as usize)?;
+        while end_block > new_blocks {
+            let start_block = end_block
+                .saturating_sub(RESIZE_BATCH_BLOCKS)
+                .max(new_blocks);
+            self.modify_disk_inode(|disk_inode| {
+                fs.punch_hole(disk_inode, start_block as u32, end_block as u32)?;
+                disk_inode.size = disk_inode.size.min((start_block * BLOCK_SZ) as u32);
+                Ok(())
+            })??;
+            fs.commit()?;
+            end_block = start_block;
+        }
+        Ok(())
+    }
+
+    /// 将文件大小修改为new_size, new_size超过文件最大大小时返回EfsError::FileTooLarge
+    ///
+    /// 缩小时从文件末尾开始分批回收数据块和不再需要的索引块, 每批作为一次事务提交,
+    /// 缩小到不超过INLINE_DATA_LIMIT时内容会移回DiskInode中;
+    /// 扩大时只修改文件大小, 新增的部分为空洞; 压缩文件以块组为单位回收
+    pub fn truncate(&self, new_size: usize) -> Result<()> {
+        self.modify_fs(|fs| {
+            let (size, max_size, compressed) = self.read_disk_inode(|disk_inode| {
+                (
+                    disk_inode.size as usize,
+                    disk_inode.max_size(),
+                    disk_inode.is_compressed(),
+                )
+            })?;
+            if new_size > max_size {
+                return Err(EfsError::FileTooLarge);
+            }
+            let new_blocks = if compressed {
+                new_size.div_ceil(COMPRESSED_CHUNK_SZ) * CHUNK_BLOCKS as usize
+            } else {
+                new_size.div_ceil(BLOCK_SZ)
+            };
+            self.shrink_blocks(fs, new_blocks)?;
+            self.modify_disk_inode(|disk_inode| {
+                // 最后一个块中新文件末尾之后的数据需要清零, 以免再次扩大时被读出
+                let tail_end = size.min(new_blocks * BLOCK_SZ);
+                self.zero_range(fs, disk_inode, new_size, tail_end)?;
+                fs.expand_inline(disk_inode, new_size)?;
+                disk_inode.size = new_size as u32;
+                fs.shrink_to_inline(disk_inode)
+            })??;
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 为文件的[offset, offset + len)范围预先分配数据块, 范围超出文件末尾时会扩大文件
+    ///
+    /// 范围超过文件最大大小时返回EfsError::FileTooLarge, 此时不会分配任何数据块;
+    /// 压缩文件的块数由内容决定, 无法预先分配, 返回EfsError::Unsupported;
+    /// 已经分配的块保持不变, 空洞部分分批分配数据块, 每批作为一次事务提交,
+    /// 空闲的数据块不足时返回EfsError::NoSpace, 此时之前的批次可能已经分配
+    pub fn fallocate(&self, offset: usize, len: usize) -> Result<()> {
+        self.modify_fs(|fs| {
+            let end = offset + len;
+            let (max_size, compressed) = self.read_disk_inode(|disk_inode| {
+                (disk_inode.max_size(), disk_inode.is_compressed())
+            })?;
+            if compressed {
+                return Err(EfsError::Unsupported);
+            }
+            if end > max_size {
+                return Err(EfsError::FileTooLarge);
+            }
+            let mut start_block = offset / BLOCK_SZ;
+            let end_block = end.div_ceil(BLOCK_SZ);
+            while start_block < end_block {
+                let batch_end = (start_block + RESIZE_BATCH_BLOCKS).min(end_block);
+                let batch_offset = (start_block * BLOCK_SZ).max(offset);
+                let batch_len = (batch_end * BLOCK_SZ).min(end) - batch_offset;
+                self.modify_disk_inode(|disk_inode| {
+                    fs.fill_holes(disk_inode, batch_offset, batch_len)
+                })??;
+                fs.commit()?;
+                start_block = batch_end;
+            }
+            Self::touch_modified(fs, self.inode_id)?;
+            fs.commit()
+        })
+    }
+
+    /// 将同一个块内[start, end)范围写为0, 这个块是空洞时不需要处理
+    ///
+    /// 压缩文件的范围位于同一个块组内, 块组重新压缩之后写入
+    fn zero_range(
+        &self,
+        fs: &mut MutexGuard<EasyFileSystem>,
+        disk_inode: &mut DiskInode,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        if start < end && disk_inode.is_compressed() {
+            // 块组的内容总是从第一个块开始保存
+            let first_block = (start / COMPRESSED_CHUNK_SZ) as u32 * CHUNK_BLOCKS;
+            if disk_inode.get_block_id(first_block, &self.block_device)? != 0 {
+                fs.write_compressed(disk_inode, start, &alloc::vec![0u8; end - start])?;
+            }
+            return Ok(());
+        }
+        if start >= end
+            || (!disk_inode.is_inline()
+                && disk_inode.get_block_id((start / BLOCK_SZ) as u32, &self.block_device)? == 0)
+        {
+            return Ok(());
+        }
+        fs.unshare(disk_inode, start, end - start)?;
+        disk_inode.write_at(start, &alloc::vec![0u8; end - start], &self.block_device)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, sync::Arc, vec, vec::Vec};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{Inode, MAX_SYMLINK_DEPTH, RELATIME_INTERVAL};
+    use crate::{
+        block_dev::BlockDevice,
+        clock::set_clock,
+        efs::EasyFileSystem,
+        fsck::fsck,
+        layout::{FEATURE_INLINE_DATA, INLINE_DATA_LIMIT},
+        test_util::{create_fs, MemDevice},
+        EfsError, BLOCK_SZ,
+    };
+
+    /// 测试中使用的时钟
+    static NOW: AtomicU32 = AtomicU32::new(1000);
+
+    fn test_clock() -> u32 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn read_updates_atime_lazily() {
+        set_clock(test_clock);
+        let (device, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let file = root.create("file").unwrap();
+        file.write_at(0, b"hello").unwrap();
+        let mut buf = [0u8; 5];
+
+        // 读取不会写入块设备, 访问时间只在块缓存中更新
+        let t = test_clock() + 10;
+        NOW.store(t, Ordering::Relaxed);
+        device.start_recording();
+        assert_eq!(file.read_at(0, &mut buf), Ok(5));
+        assert!(device.take_writes().is_empty());
+        assert_eq!(file.stat().unwrap().atime, t);
+
+        // 访问时间已经晚于修改时间, 一天之内再次读取不会更新它
+        NOW.store(t + 10, Ordering::Relaxed);
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(file.stat().unwrap().atime, t);
+        NOW.store(t + RELATIME_INTERVAL, Ordering::Relaxed);
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(file.stat().unwrap().atime, t + RELATIME_INTERVAL);
+
+        // 更新后的访问时间随下一次提交的事务写入磁盘
+        root.create("other").unwrap();
+        let reopened =
+            EasyFileSystem::open(Arc::new(MemDevice::from_image(device.image()))).unwrap();
+        let file = Inode::root_inode(&reopened).find("file").unwrap();
+        assert_eq!(file.stat().unwrap().atime, t + RELATIME_INTERVAL);
+    }
+
+    #[test]
+    fn fallocate_and_truncate_keep_free_counts_exact() {
+        let (device, efs) = create_fs(4096, 0);
+        let file = Inode::root_inode(&efs).create("file").unwrap();
+        let free = efs.lock().free_data_blocks().unwrap();
+        let image = device.image();
+
+        // 每一批分配的块和空闲计数在同一次事务中提交, 在任意一次写入之后崩溃都不需要重新计算
+        device.start_recording();
+        file.fallocate(BLOCK_SZ, 100 * BLOCK_SZ).unwrap();
+        let writes = device.take_writes();
+        for count in 0..=writes.len() {
+            let crashed: Arc<dyn BlockDevice> = Arc::new(MemDevice::from_image(
+                MemDevice::crash_image(&image, &writes, count),
+            ));
+            let efs = EasyFileSystem::open(crashed).unwrap();
+            let report = fsck(&efs, false).unwrap();
+            assert!(
+                report.is_clean(),
+                "crash after write {}: {:?}",
+                count,
+                report
+            );
+        }
+
+        assert_eq!(file.stat().unwrap().size as usize, 101 * BLOCK_SZ);
+        let allocated = free - efs.lock().free_data_blocks().unwrap();
+        assert!(allocated >= 100);
+        let mut buf = vec![1u8; 101 * BLOCK_SZ];
+        assert_eq!(file.read_at(0, &mut buf), Ok(101 * BLOCK_SZ));
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        // 已经分配的块保持不变, 再次预先分配不会占用更多的块
+        file.fallocate(0, 50 * BLOCK_SZ).unwrap();
+        assert_eq!(free - efs.lock().free_data_blocks().unwrap(), allocated + 1);
+
+        file.truncate(10).unwrap();
+        assert_eq!(file.stat().unwrap().size, 10);
+        file.truncate(0).unwrap();
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn find_create_ls_read_write_clear() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let free = efs.lock().free_data_blocks().unwrap();
+        let a = root.create("a").unwrap();
+        root.create("b").unwrap();
+        assert_eq!(root.create("a").err(), Some(EfsError::Exists));
+        assert_eq!(root.find("c").err(), Some(EfsError::NotFound));
+        let names = root.ls().unwrap();
+        assert!(names.iter().any(|name| name == "a") && names.iter().any(|name| name == "b"));
+
+        let data: Vec<u8> = (0..3 * BLOCK_SZ + 7).map(|i| i as u8).collect();
+        assert_eq!(a.write_at(0, &data), Ok(data.len()));
+        let found = root.find("a").unwrap();
+        assert_eq!(found.inode_id(), a.inode_id());
+        let mut buf = vec![0u8; data.len() + 10];
+        assert_eq!(found.read_at(0, &mut buf), Ok(data.len()));
+        assert_eq!(&buf[..data.len()], &data[..]);
+        assert_eq!(found.read_at(data.len(), &mut buf), Ok(0));
+
+        a.clear().unwrap();
+        assert_eq!(a.stat().unwrap().size, 0);
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn mkdir_rmdir_and_path_lookup() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let free_inodes = efs.lock().free_inodes().unwrap();
+        let a = root.mkdir("a").unwrap();
+        let b = a.mkdir("b").unwrap();
+        let c = b.create("c").unwrap();
+        assert!(a.is_dir().unwrap() && c.is_file().unwrap());
+
+        // 相对路径、绝对路径以及"."和".."
+        assert_eq!(root.find("a/b/c").unwrap().inode_id(), c.inode_id());
+        assert_eq!(a.find("b/c").unwrap().inode_id(), c.inode_id());
+        assert_eq!(b.find("/a").unwrap().inode_id(), a.inode_id());
+        assert_eq!(b.find("..").unwrap().inode_id(), a.inode_id());
+        assert_eq!(root.find("a/./b/../b/c").unwrap().inode_id(), c.inode_id());
+        assert_eq!(root.find("..").unwrap().inode_id(), root.inode_id());
+        assert_eq!(root.find("a/b/c/d").err(), Some(EfsError::NotDir));
+        assert_eq!(root.find("a/x/c").err(), Some(EfsError::NotFound));
+
+        assert_eq!(a.rmdir("b").err(), Some(EfsError::NotEmpty));
+        assert_eq!(b.rmdir("c").err(), Some(EfsError::NotDir));
+        b.unlink("c").unwrap();
+        a.rmdir("b").unwrap();
+        root.rmdir("a").unwrap();
+        assert_eq!(root.find("a").err(), Some(EfsError::NotFound));
+        assert_eq!(efs.lock().free_inodes(), Ok(free_inodes));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn hard_links_share_inode_until_last_unlink() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let dir = root.mkdir("dir").unwrap();
+        let (free_inodes, free_blocks) = {
+            let fs = efs.lock();
+            (fs.free_inodes().unwrap(), fs.free_data_blocks().unwrap())
+        };
+        let file = root.create("f").unwrap();
+        file.write_at(0, &[6u8; 2 * BLOCK_SZ]).unwrap();
+        dir.link("/f", "g").unwrap();
+        assert_eq!(file.nlink(), Ok(2));
+        assert_eq!(dir.find("g").unwrap().inode_id(), file.inode_id());
+        assert_eq!(root.link("dir", "d2").err(), Some(EfsError::IsDir));
+        assert_eq!(root.unlink("dir").err(), Some(EfsError::IsDir));
+        assert_eq!(dir.link("/f", "g").err(), Some(EfsError::Exists));
+
+        // 删除一个目录项之后内容仍然可以通过另一个目录项访问
+        root.unlink("f").unwrap();
+        let g = dir.find("g").unwrap();
+        assert_eq!(g.nlink(), Ok(1));
+        let mut buf = [0u8; 2 * BLOCK_SZ];
+        assert_eq!(g.read_at(0, &mut buf), Ok(2 * BLOCK_SZ));
+        assert!(buf.iter().all(|&byte| byte == 6));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+
+        // 最后一个目录项被删除时回收inode和数据块
+        dir.unlink("g").unwrap();
+        assert_eq!(efs.lock().free_inodes(), Ok(free_inodes));
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free_blocks));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn symlinks_resolve_up_to_depth_limit() {
+        let (_, efs) = create_fs(4096, 0);
+        let root = Inode::root_inode(&efs);
+        let dir = root.mkdir("dir").unwrap();
+        let target = dir.create("target").unwrap();
+        // 相对路径从链接所在的目录开始查找
+        dir.symlink("target", "rel").unwrap();
+        root.symlink("/dir/target", "l0").unwrap();
+        assert_eq!(root.find("dir/rel").unwrap().inode_id(), target.inode_id());
+        let link = root.lookup("l0", false).unwrap();
+        assert!(link.is_symlink().unwrap());
+        assert_eq!(link.readlink().unwrap(), "/dir/target");
+        assert_eq!(target.readlink().err(), Some(EfsError::InvalidArgument));
+
+        // l{n}指向l{n-1}, 查找l{n}需要跟随n + 1层链接
+        for i in 1..=MAX_SYMLINK_DEPTH {
+            root.symlink(&format!("l{}", i - 1), &format!("l{}", i))
+                .unwrap();
+        }
+        let last = MAX_SYMLINK_DEPTH - 1;
+        assert_eq!(
+            root.find(&format!("l{}", last)).unwrap().inode_id(),
+            target.inode_id()
+        );
+        assert_eq!(
+            root.find(&format!("l{}", last + 1)).err(),
+            Some(EfsError::TooManyLinks)
+        );
+        // 不跟随最后一个部分时不受层数限制
+        assert!(root
+            .lookup(&format!("l{}", last + 1), false)
+            .unwrap()
+            .is_symlink()
+            .unwrap());
+
+        root.symlink("loop_b", "loop_a").unwrap();
+        root.symlink("loop_a", "loop_b").unwrap();
+        assert_eq!(root.find("loop_a").err(), Some(EfsError::TooManyLinks));
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn inline_data_moves_to_blocks_and_back() {
+        let (_, efs) = create_fs(4096, FEATURE_INLINE_DATA);
+        let file = Inode::root_inode(&efs).create("file").unwrap();
+        let free = efs.lock().free_data_blocks().unwrap();
+        let data: Vec<u8> = (0..INLINE_DATA_LIMIT + 1).map(|i| i as u8 | 1).collect();
+
+        // 不超过INLINE_DATA_LIMIT的内容直接保存在DiskInode中, 不占用数据块
+        file.write_at(0, &data[..INLINE_DATA_LIMIT]).unwrap();
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free));
+
+        // 超过之后内容移到数据块中
+        file.write_at(INLINE_DATA_LIMIT, &data[INLINE_DATA_LIMIT..])
+            .unwrap();
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free - 1));
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+
+        // 截断到INLINE_DATA_LIMIT时移回DiskInode中, 扩大时新增的部分读出为0
+        file.truncate(INLINE_DATA_LIMIT).unwrap();
+        assert_eq!(efs.lock().free_data_blocks(), Ok(free));
+        file.truncate(INLINE_DATA_LIMIT + 1).unwrap();
+        assert_eq!(file.read_at(0, &mut buf), Ok(data.len()));
+        assert_eq!(&buf[..INLINE_DATA_LIMIT], &data[..INLINE_DATA_LIMIT]);
+        assert_eq!(buf[INLINE_DATA_LIMIT], 0);
+        assert!(fsck(&efs, false).unwrap().is_clean());
+    }
+}
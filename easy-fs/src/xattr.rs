@@ -0,0 +1,120 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::BLOCK_SZ;
+
+/// 扩展属性名的最大长度
+pub const XATTR_NAME_LIMIT: usize = 255;
+/// 每个扩展属性条目头部的字节数: 名字长度(1字节) + 值长度(2字节)
+const XATTR_HEADER_SZ: usize = 3;
+
+/// 扩展属性块
+///
+/// 每个inode最多有一个扩展属性块, 块中依次存放各个条目:
+/// 名字长度(u8) | 值长度(u16, 小端序) | 名字 | 值,
+/// 名字长度为0的条目表示后面没有更多的条目
+pub type XattrBlock = [u8; BLOCK_SZ];
+
+/// 一个扩展属性条目, (名字, 值)
+pub type XattrEntry = (String, Vec<u8>);
+
+/// 解析扩展属性块中的所有条目
+///
+/// 遇到超出块范围的条目时停止解析, 只返回之前完整的条目
+pub fn decode(block: &XattrBlock) -> Vec<XattrEntry> {
+    let mut entries: Vec<XattrEntry> = Vec::new();
+    let mut pos = 0;
+    while pos + XATTR_HEADER_SZ <= BLOCK_SZ {
+        let name_len = block[pos] as usize;
+        let value_len = u16::from_le_bytes([block[pos + 1], block[pos + 2]]) as usize;
+        if name_len == 0 {
+            break;
+        }
+        let name_start = pos + XATTR_HEADER_SZ;
+        let value_start = name_start + name_len;
+        if value_start + value_len > BLOCK_SZ {
+            break;
+        }
+        let name = match core::str::from_utf8(&block[name_start..value_start]) {
+            Ok(name) => String::from(name),
+            Err(_) => break,
+        };
+        entries.push((name, block[value_start..value_start + value_len].to_vec()));
+        pos = value_start + value_len;
+    }
+    entries
+}
+
+/// 将条目写成一个扩展属性块, 放不下或者名字、值的长度超出头部能表示的范围时返回None
+pub fn encode(entries: &[XattrEntry]) -> Option<XattrBlock> {
+    let mut block = [0u8; BLOCK_SZ];
+    let mut pos = 0;
+    for (name, value) in entries {
+        if name.is_empty() || name.len() > XATTR_NAME_LIMIT || value.len() > u16::MAX as usize {
+            return None;
+        }
+        let end = pos + XATTR_HEADER_SZ + name.len() + value.len();
+        if end > BLOCK_SZ {
+            return None;
+        }
+        block[pos] = name.len() as u8;
+        block[pos + 1..pos + 3].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        block[pos + XATTR_HEADER_SZ..pos + XATTR_HEADER_SZ + name.len()]
+            .copy_from_slice(name.as_bytes());
+        block[pos + XATTR_HEADER_SZ + name.len()..end].copy_from_slice(value);
+        pos = end;
+    }
+    Some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec, vec::Vec};
+
+    use super::{decode, encode, XattrEntry, XATTR_HEADER_SZ, XATTR_NAME_LIMIT};
+    use crate::BLOCK_SZ;
+
+    fn entry(name: &str, value: &[u8]) -> XattrEntry {
+        (String::from(name), value.to_vec())
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let entries = vec![entry("user.a", b"1"), entry("user.empty", b"")];
+        assert_eq!(decode(&encode(&entries).unwrap()), entries);
+        // 恰好填满整个块的条目
+        let value = vec![7u8; BLOCK_SZ - XATTR_HEADER_SZ - 1];
+        let full = vec![entry("x", &value)];
+        assert_eq!(decode(&encode(&full).unwrap()), full);
+    }
+
+    #[test]
+    fn encode_rejects_entries_out_of_bounds() {
+        assert!(encode(&[entry("", b"1")]).is_none());
+        let long_name: String = "n".repeat(XATTR_NAME_LIMIT + 1);
+        assert!(encode(&[entry(&long_name, b"1")]).is_none());
+        assert!(encode(&[entry("x", &vec![0u8; BLOCK_SZ - XATTR_HEADER_SZ])]).is_none());
+        assert!(encode(&[entry("x", &vec![0u8; u16::MAX as usize + 1])]).is_none());
+    }
+
+    #[test]
+    fn decode_stops_at_truncated_or_invalid_entry() {
+        let mut block = encode(&[entry("user.a", b"1"), entry("user.b", b"2")]).unwrap();
+        let second = XATTR_HEADER_SZ + "user.a".len() + 1;
+        let first: Vec<XattrEntry> = vec![entry("user.a", b"1")];
+
+        // 值超出块的范围
+        let mut truncated = block;
+        truncated[second + 1..second + 3].copy_from_slice(&(BLOCK_SZ as u16).to_le_bytes());
+        assert_eq!(decode(&truncated), first);
+
+        // 名字不是合法的UTF-8
+        block[second + XATTR_HEADER_SZ] = 0xff;
+        assert_eq!(decode(&block), first);
+
+        // 块末尾剩下的字节放不下条目头部
+        let filler = vec![entry("x", &vec![7u8; BLOCK_SZ - XATTR_HEADER_SZ - 3])];
+        let mut tail = encode(&filler).unwrap();
+        tail[BLOCK_SZ - 2] = 1;
+        assert_eq!(decode(&tail), filler);
+    }
+}